@@ -19,7 +19,10 @@ use kafka::producer::{
 };
 use rs_utils::config::{Config, Kratos};
 
-use crate::permission::iam_client::IamClient;
+use crate::{
+    permission::iam_client::IamClient,
+    utils::{cache::KratosCache, index::MemberIndex, lock::LockManager, queue::JobQueue},
+};
 
 pub const CONFIG_FALLBACK: &str = "test/config.toml";
 
@@ -90,9 +93,16 @@ pub struct Service {
 }
 
 /// Structure representing the iam connection config.
+/// The syncs send the version of their group only if the iam server checks it, they compare
+/// it with a read of the group otherwise, and a whole perm_type field is only written with an
+/// empty resource if the iam server replaces it.
 #[derive(Deserialize, Clone, Default, Debug)]
 pub struct Iam {
     pub service: Service,
+    #[serde(default)]
+    pub versioned: bool,
+    #[serde(default)]
+    pub whole_field: bool,
     #[serde(skip)]
     pub client: Option<IamClient<Channel>>,
 }
@@ -104,6 +114,211 @@ pub struct Opa {
     pub mode: String,
 }
 
+/// Structure representing the kratos schemas used to create groups and organisations.
+#[derive(Deserialize, Clone, Debug)]
+pub struct Schemas {
+    pub organisation: String,
+    pub group: String,
+}
+
+impl Schemas {
+    /// Get the schema of a kind of identity, a group or an organisation.
+    pub fn of(&self, kind: &str) -> &str {
+        match kind {
+            "group" => &self.group,
+            _ => &self.organisation,
+        }
+    }
+}
+
+impl Default for Schemas {
+    fn default() -> Self {
+        Schemas {
+            organisation: "organisation".to_owned(),
+            group: "group".to_owned(),
+        }
+    }
+}
+
+/// Structure representing the actions (http methods) allowed for each role, used to authorize
+/// the callers when the opa feature is disabled, and the hierarchy of the roles, ordered from
+/// the lowest to the highest, used to forbid the grant of a role above the one of the caller.
+/// The groups and organisations must always keep a user holding one of the admin roles.
+/// The callers of the permission check of a given identity send the check token as a bearer,
+/// the route is disabled without it.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct Authorization {
+    pub roles: HashMap<String, Vec<String>>,
+    pub hierarchy: Vec<String>,
+    pub admin_roles: Vec<String>,
+    pub check_token: Option<String>,
+}
+
+impl Default for Authorization {
+    fn default() -> Self {
+        let actions = vec!["post".to_owned(), "put".to_owned(), "delete".to_owned()];
+        Authorization {
+            roles: HashMap::from([
+                ("owner".to_owned(), actions.clone()),
+                ("admin".to_owned(), actions),
+            ]),
+            hierarchy: ["viewer", "contributor", "editor", "admin", "owner"]
+                .iter()
+                .map(|role| role.to_string())
+                .collect(),
+            admin_roles: vec!["owner".to_owned(), "admin".to_owned()],
+            check_token: None,
+        }
+    }
+}
+
+impl Authorization {
+    /// Check if one of the roles allow the action.
+    pub fn allows(&self, roles: &[String], action: &str) -> bool {
+        roles.iter().any(|role| match self.roles.get(role) {
+            Some(actions) => actions.iter().any(|allowed| allowed == action),
+            None => false,
+        })
+    }
+
+    /// Check if one of the roles is an admin role.
+    pub fn is_admin(&self, roles: &[String]) -> bool {
+        roles.iter().any(|role| self.admin_roles.contains(role))
+    }
+
+    /// Check if the token is the check token, in a time independent of their content.
+    pub fn is_check_token(&self, token: &str) -> bool {
+        let Some(expected) = &self.check_token else {
+            return false;
+        };
+        expected.len() == token.len()
+            && expected
+                .bytes()
+                .zip(token.bytes())
+                .fold(0, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+
+    /// Get the rank of a role in the hierarchy, the roles outside of it are not ranked.
+    pub fn rank(&self, role: &str) -> Option<usize> {
+        self.hierarchy.iter().position(|ranked| ranked == role)
+    }
+
+    /// Check if the holder of the roles can grant the role, which must not be ranked above the
+    /// highest of them. A role outside of the hierarchy can never be granted.
+    pub fn can_grant(&self, roles: &[String], role: &str) -> bool {
+        let held = roles.iter().filter_map(|held| self.rank(held)).max();
+        match (held, self.rank(role)) {
+            (Some(held), Some(granted)) => granted <= held,
+            _ => false,
+        }
+    }
+}
+
+/// Structure representing who can create organisations, anyone when they are open and only
+/// the listed creators otherwise.
+#[derive(Deserialize, Clone, Default, Debug)]
+#[serde(default)]
+pub struct Organisations {
+    pub open: bool,
+    pub creators: Vec<String>,
+}
+
+impl Organisations {
+    /// Check if the identity can create an organisation.
+    pub fn can_create(&self, id: &str) -> bool {
+        self.open || self.creators.iter().any(|creator| creator == id)
+    }
+}
+
+/// Structure representing the periodic reconciliation config, the interval is in seconds and
+/// the budget is the number of organisations reconciled with their groups per run. The drifts
+/// are only reported unless repair is set.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct Reconcile {
+    pub enabled: bool,
+    pub interval: u64,
+    pub concurrency: usize,
+    pub budget: usize,
+    pub repair: bool,
+}
+
+impl Default for Reconcile {
+    fn default() -> Self {
+        Reconcile {
+            enabled: false,
+            interval: 3600,
+            concurrency: 4,
+            budget: 100,
+            repair: false,
+        }
+    }
+}
+
+/// Structure representing the sync job queue config, the backoffs are in seconds as the
+/// retention of the finished jobs, kept to be queried by their owner, and the drain, the time
+/// given to the running job to finish on shutdown.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct Jobs {
+    pub path: PathBuf,
+    pub max_attempts: u32,
+    pub backoff: u64,
+    pub max_backoff: u64,
+    pub retention: u64,
+    pub drain: u64,
+}
+
+impl Default for Jobs {
+    fn default() -> Self {
+        Jobs {
+            path: PathBuf::from("sirius.db"),
+            max_attempts: 5,
+            backoff: 1,
+            max_backoff: 300,
+            retention: 86400,
+            drain: 30,
+        }
+    }
+}
+
+/// Structure representing the concurrency limits of the updates: the number of distinct
+/// identities fetched from kratos at a time and of calls to iam in flight.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct Limits {
+    pub kratos: usize,
+    pub iam: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits { kratos: 8, iam: 32 }
+    }
+}
+
+/// Structure representing the kratos cache config, the ttl of the sessions and identities is
+/// in seconds, 0 disabling the cache, and their number is capped by the sizes.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct Cache {
+    pub ttl: u64,
+    pub sessions: usize,
+    pub identities: usize,
+}
+
+impl Default for Cache {
+    fn default() -> Self {
+        Cache {
+            ttl: 30,
+            sessions: 1000,
+            identities: 10000,
+        }
+    }
+}
+
 /// Structure containing the configuaration of the application.
 #[derive(Deserialize, Clone, Default, Debug)]
 pub struct SiriusConfig {
@@ -113,6 +328,28 @@ pub struct SiriusConfig {
     pub opa: Opa,
     pub kratos: Kratos,
     pub kafka: Kafka,
+    #[serde(default)]
+    pub schemas: Schemas,
+    #[serde(default)]
+    pub authorization: Authorization,
+    #[serde(default)]
+    pub organisations: Organisations,
+    #[serde(default)]
+    pub reconcile: Reconcile,
+    #[serde(default)]
+    pub jobs: Jobs,
+    #[serde(default)]
+    pub limits: Limits,
+    #[serde(default)]
+    pub cache: Cache,
+    #[serde(skip)]
+    pub index: Arc<MemberIndex>,
+    #[serde(skip)]
+    pub locks: Arc<LockManager>,
+    #[serde(skip)]
+    pub kratos_cache: Arc<KratosCache>,
+    #[serde(skip)]
+    pub queue: Option<Arc<JobQueue>>,
     #[serde(skip)]
     path: Option<PathBuf>,
 }
@@ -151,6 +388,10 @@ impl Config for SiriusConfig {
         config.iam.update()?;
         config.set_path(path);
         config.kafka.update()?;
+        config.index = self.index.clone();
+        config.locks = self.locks.clone();
+        config.kratos_cache = self.kratos_cache.clone();
+        config.queue = self.queue.clone();
         *self = config;
         Ok(())
     }
@@ -176,6 +417,35 @@ mod test_config {
         assert!(res.is_err())
     }
 
+    #[test]
+    fn test_authorization_allows() {
+        let authorization = Authorization::default();
+        assert!(authorization.allows(&["viewer".to_owned(), "admin".to_owned()], "delete"));
+        assert!(!authorization.allows(&["viewer".to_owned()], "post"));
+        assert!(!authorization.allows(&[], "post"));
+    }
+
+    #[test]
+    fn test_authorization_can_grant() {
+        let authorization = Authorization::default();
+        let roles = ["viewer".to_owned(), "admin".to_owned()];
+        assert!(authorization.can_grant(&roles, "admin"));
+        assert!(authorization.can_grant(&roles, "contributor"));
+        assert!(!authorization.can_grant(&roles, "owner"));
+        assert!(!authorization.can_grant(&roles, "unknown"));
+        assert!(!authorization.can_grant(&[], "viewer"));
+    }
+
+    #[test]
+    fn test_authorization_check_token() {
+        let mut authorization = Authorization::default();
+        assert!(!authorization.is_check_token(""));
+        authorization.check_token = Some("secret".to_owned());
+        assert!(authorization.is_check_token("secret"));
+        assert!(!authorization.is_check_token("secreT"));
+        assert!(!authorization.is_check_token("secret2"));
+    }
+
     #[tokio::test]
     async fn test_update_iam() {
         let mut config = SiriusConfig::default();
@@ -0,0 +1,168 @@
+use std::collections::BTreeMap;
+
+use anyhow::{bail, Result};
+use ory_kratos_client::apis::identity_api::get_identity;
+use serde::Serialize;
+use serde_json::{json, Value};
+use tracing::info;
+
+use crate::{
+    config::SiriusConfig,
+    controller::{
+        sync::Patch,
+        update::{ItemResult, Operation},
+    },
+    metadata::raw_metadata,
+    router::Data,
+};
+
+/// Structure representing the metadata of an identity before and after an update.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Diff {
+    pub id: String,
+    pub before: Value,
+    pub after: Value,
+}
+
+/// Build the patches of the payload items resolved to an identity.
+/// The additions and replacements both set the value of the permission.
+pub fn item_patches(payload: &[Data], items: &[ItemResult], operation: Operation) -> Vec<Patch> {
+    payload
+        .iter()
+        .zip(items)
+        .filter_map(|(data, item)| {
+            Some(Patch {
+                id: item.identity.clone()?,
+                perm_type: data.ressource_type.clone(),
+                resource: data.ressource_id.clone(),
+                value: match operation {
+                    Operation::Add | Operation::Replace => Some(data.value.clone()),
+                    Operation::Remove => None,
+                },
+            })
+        })
+        .collect()
+}
+
+/// Check if an entry of a list of ids is the resource.
+fn is_resource(id: &Value, resource: &str) -> bool {
+    *id == resource || resource.parse::<u64>().is_ok_and(|number| *id == number)
+}
+
+/// Apply a patch on the metadata of an identity, the resources listed in an array are added
+/// or removed from it.
+fn apply(metadata: &mut Value, patch: &Patch) {
+    if !metadata.is_object() {
+        *metadata = json!({});
+    }
+    let entries = &mut metadata[&patch.perm_type];
+    match (entries, &patch.value) {
+        (Value::Array(ids), value) => {
+            ids.retain(|id| !is_resource(id, &patch.resource));
+            if value.is_some() {
+                ids.push(Value::String(patch.resource.clone()));
+            }
+        }
+        (Value::Object(entries), None) => {
+            entries.remove(&patch.resource);
+        }
+        (entries, Some(value)) => {
+            if !entries.is_object() {
+                *entries = json!({});
+            }
+            entries[&patch.resource] = value.clone();
+        }
+        (_, None) => (),
+    }
+}
+
+/// Compute the metadata of every identity affected by the patches before and after they are
+/// applied, nothing is sent to iam.
+pub async fn diff_controller(config: &SiriusConfig, patches: &[Patch]) -> Result<Vec<Diff>> {
+    let Some(client) = &config.kratos.client else {
+        bail!("kratos client not initialized")
+    };
+    let mut affected: BTreeMap<&str, Vec<&Patch>> = BTreeMap::new();
+    for patch in patches {
+        affected.entry(&patch.id).or_default().push(patch);
+    }
+    let mut diffs = Vec::new();
+    for (id, patches) in affected {
+        info!("computing the diff of {id}");
+        let identity = get_identity(client, id, None).await?;
+        let before = raw_metadata(config, &identity)?
+            .cloned()
+            .unwrap_or_else(|| json!({}));
+        let mut after = before.clone();
+        for patch in patches {
+            apply(&mut after, patch);
+        }
+        diffs.push(Diff {
+            id: id.to_owned(),
+            before,
+            after,
+        });
+    }
+    Ok(diffs)
+}
+
+#[cfg(test)]
+mod test_diff {
+    use mockito::Server as MockServer;
+
+    use super::*;
+    use crate::utils::test::{configure, IDENTITY_GROUP_ADMIN};
+
+    fn patch(perm_type: &str, resource: &str, value: Option<Value>) -> Patch {
+        Patch {
+            id: "af25f904-5319-4011-95a4-343365d64811".to_owned(),
+            perm_type: perm_type.to_owned(),
+            resource: resource.to_owned(),
+            value,
+        }
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut metadata = json!({"project": [122, 334], "user": {"1": ["admin"]}});
+        apply(&mut metadata, &patch("project", "122", None));
+        apply(&mut metadata, &patch("project", "456", Some(json!([]))));
+        apply(&mut metadata, &patch("user", "1", None));
+        apply(&mut metadata, &patch("user", "2", Some(json!(["viewer"]))));
+        apply(&mut metadata, &patch("group", "3", Some(json!("awesome"))));
+        assert_eq!(
+            metadata,
+            json!({
+                "project": [334, "456"],
+                "user": {"2": ["viewer"]},
+                "group": {"3": "awesome"}
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_diff_controller() {
+        let mut kratos_server = MockServer::new_async().await;
+        let config = configure(Some(&kratos_server), None, None).await;
+        let kratos_mock = kratos_server
+            .mock(
+                "GET",
+                "/admin/identities/af25f904-5319-4011-95a4-343365d64811",
+            )
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(IDENTITY_GROUP_ADMIN)
+            .create_async()
+            .await;
+        let patches = [
+            patch("user", "1", Some(json!(["viewer"]))),
+            patch("project", "122", None),
+        ];
+        let diffs = diff_controller(&config, &patches).await.unwrap();
+        kratos_mock.assert_async().await;
+        assert_eq!(diffs.len(), 1);
+        assert_eq!(diffs[0].before["project"], json!([122, 334, 456]));
+        assert_eq!(diffs[0].after["project"], json!([334, 456]));
+        assert_eq!(diffs[0].after["user"]["1"], json!(["viewer"]));
+    }
+}
@@ -2,122 +2,277 @@ use std::collections::{HashMap, HashSet};
 
 use anyhow::{anyhow, bail, Ok, Result};
 use ory_kratos_client::models::Identity;
+use serde::Serialize;
 use serde_json::Value;
 
-use tracing::{debug, error, info};
+use tracing::{debug, info};
 
-use crate::config::SiriusConfig;
+use crate::{
+    config::SiriusConfig,
+    metadata::{parse_metadata, Entry, Projects},
+};
 
-/// Populate the projects id HashSet with the given data.
-fn populate_set(projects: &mut HashSet<String>, mut data: Value) -> Result<()> {
-    let data = data.take();
-    match data {
-        Value::Object(map) => {
-            if !map.is_empty() {
-                for (key, _) in &map {
-                    projects.insert(key.to_owned());
-                }
-            }
-        }
-        Value::Array(array) => {
-            if !array.is_empty() {
-                for project in &array {
-                    let project = project
-                        .as_str()
-                        .ok_or_else(|| anyhow!(" this shoud be a string"))?;
-                    projects.insert(project.to_owned());
-                }
+/// Enum representing where a permission on a project comes from.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Source {
+    Direct,
+    Group { id: String, name: String },
+    Organisation { id: String, name: String },
+}
+
+/// Structure representing the roles held on a project and where they come from.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Grant {
+    pub roles: Vec<String>,
+    pub source: Source,
+}
+
+/// Structure representing a group or an organisation the identity is member of.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Membership {
+    pub name: String,
+    pub role: Vec<String>,
+    pub project: Vec<String>,
+}
+
+/// Structure representing the details of a group or an organisation.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Details {
+    pub id: String,
+    pub name: Value,
+    pub project: Vec<String>,
+    pub user: HashMap<String, Vec<String>>,
+    pub group: HashMap<String, String>,
+}
+
+/// Add the grants found in the given projects to the grants hashmap.
+/// The inherited roles are added to every projects found.
+fn populate_grants(
+    grants: &mut HashMap<String, Vec<Grant>>,
+    projects: &Projects,
+    inherited: &[String],
+    source: &Source,
+) {
+    for (project, mut roles) in projects.roles() {
+        for role in inherited {
+            if !roles.contains(role) {
+                roles.push(role.to_owned());
             }
         }
-        _ => bail!("This should be a map or an array!"),
+        grants.entry(project).or_default().push(Grant {
+            roles,
+            source: source.clone(),
+        });
     }
-    Ok(())
 }
 
-/// Extract the projects id from the identity and store them in the projects hashset.
-fn extract_projects(projects: &mut HashSet<String>, mut data: Value) -> Result<()> {
-    debug!("{data:?}");
-    let data = data
-        .as_object_mut()
-        .ok_or_else(|| anyhow!("this should be a map!"))?;
-    if !data.is_empty() {
-        for (_, val) in &mut *data {
-            if let Some(proj) = val.get_mut("project") {
-                populate_set(projects, proj.take())?;
-            }
-        }
+/// Extract the memberships from the group or organisation entries.
+fn extract_memberships(entries: &HashMap<String, Entry>) -> HashMap<String, Membership> {
+    entries
+        .iter()
+        .map(|(id, entry)| {
+            let membership = Membership {
+                name: entry.name.clone(),
+                role: entry.role.0.clone(),
+                project: entry.project.ids(),
+            };
+            (id.to_owned(), membership)
+        })
+        .collect()
+}
+
+/// Add the grants of every group or organisation entry to the grants hashmap.
+fn extract_membership_grants(
+    grants: &mut HashMap<String, Vec<Grant>>,
+    entries: &HashMap<String, Entry>,
+    data_type: &str,
+) {
+    debug!("{entries:?}");
+    for (id, entry) in entries {
+        let source = match data_type {
+            "group" => Source::Group {
+                id: id.to_owned(),
+                name: entry.name.clone(),
+            },
+            _ => Source::Organisation {
+                id: id.to_owned(),
+                name: entry.name.clone(),
+            },
+        };
+        populate_grants(grants, &entry.project, &entry.role.0, &source);
     }
-    Ok(())
 }
 
-/// extract projects id from the identity in project, group and organisation and returns them in a
-/// hashset.
+/// extract projects from the identity in project, group and organisation and returns for each
+/// of them the roles held and where they come from.
 pub async fn list_project_controller(
-    identity: Identity,
+    identity: &Identity,
     config: &SiriusConfig,
-) -> Result<HashSet<String>> {
-    let mut projects = HashSet::new();
-    let meta = match &config.opa.mode as &str {
-        "admin" => identity.metadata_admin,
-        "public" => identity.metadata_public,
-        "trait" => identity.traits,
-        _ => bail!("Invalid mode! please put a valid mode (admin, public or trait) in the config"),
-    };
+) -> Result<HashMap<String, Vec<Grant>>> {
+    let mut grants = HashMap::new();
+    let metadata = parse_metadata(config, identity)?;
+    info!("extracting project from project");
+    populate_grants(&mut grants, &metadata.project, &[], &Source::Direct);
+    info!("extracting project from group");
+    extract_membership_grants(&mut grants, &metadata.group, "group");
+    info!("extracting project from orga");
+    extract_membership_grants(&mut grants, &metadata.organisation, "organisation");
+    Ok(grants)
+}
 
-    let mut metadata = if let Some(mut metadata) = meta {
-        metadata.take()
-    } else {
-        error!("no metadata in this user!");
-        bail!("no metadata in this user!")
+/// Extract the roles of the identity on each project, merged from project, group and
+/// organisation.
+pub async fn list_roles_controller(
+    identity: &Identity,
+    config: &SiriusConfig,
+) -> Result<HashMap<String, HashSet<String>>> {
+    let grants = list_project_controller(identity, config).await?;
+    let roles = grants
+        .into_iter()
+        .map(|(project, grants)| {
+            let roles = grants.into_iter().flat_map(|grant| grant.roles).collect();
+            (project, roles)
+        })
+        .collect();
+    Ok(roles)
+}
+
+/// Check if the identity has the given role on the project.
+pub async fn check_controller(
+    identity: &Identity,
+    project: &str,
+    role: &str,
+    config: &SiriusConfig,
+) -> Result<bool> {
+    let roles = list_roles_controller(identity, config).await?;
+    let allowed = match roles.get(project) {
+        Some(roles) => roles.contains(role),
+        None => false,
     };
-    if let Some(data) = metadata.get_mut("project") {
-        info!("extracting project from project");
-        populate_set(&mut projects, data.take())?;
-    }
-    if let Some(group) = metadata.get_mut("group") {
-        info!("extracting project from group");
-        extract_projects(&mut projects, group.take())?;
-    }
-    if let Some(orga) = metadata.get_mut("organisation") {
-        info!("extracting project from orga");
-        extract_projects(&mut projects, orga.take())?;
-    }
-    Ok(projects)
+    debug!("{project}: {role} => {allowed}");
+    Ok(allowed)
 }
 
-/// Extract the ask data type of a given identity.
+/// Extract the ask data type of a given identity with the roles held and the projects.
 /// Where it recuperate them is configured with mode in the opa section of te config file.
 pub async fn list_controller(
     identity: Identity,
     data_type: &str,
     config: &SiriusConfig,
-) -> Result<HashMap<String, String>> {
-    let mut projects = HashMap::new();
-    let meta = match &config.opa.mode as &str {
-        "admin" => &identity.metadata_admin,
-        "public" => &identity.metadata_public,
-        "trait" => &identity.traits,
-        _ => bail!("Invalid mode! please put a valid mode (admin, public or trait) in the config"),
+) -> Result<HashMap<String, Membership>> {
+    let metadata = parse_metadata(config, &identity)?;
+    info!("extracting: {data_type}");
+    let memberships = extract_memberships(metadata.entries(data_type)?);
+    Ok(memberships)
+}
+
+/// Extract the name, projects, users and sub-groups of a group or organisation identity.
+pub async fn detail_controller(identity: &Identity, config: &SiriusConfig) -> Result<Details> {
+    let metadata = parse_metadata(config, identity)?;
+    let name = match identity.traits {
+        Some(ref traits) => traits
+            .get("name")
+            .ok_or_else(|| anyhow!("this identity as no name!"))?
+            .clone(),
+        None => bail!("this identity as no trait!"),
     };
+    let project = metadata.project.ids();
+    let user = metadata
+        .user
+        .into_iter()
+        .map(|(id, roles)| (id, roles.0))
+        .collect();
+    let group = metadata
+        .group
+        .into_iter()
+        .map(|(id, entry)| (id, entry.name))
+        .collect();
+    Ok(Details {
+        id: identity.id.clone(),
+        name,
+        project,
+        user,
+        group,
+    })
+}
 
-    let Some(metadata) = meta else {
-        error!("no metadata in this user!");
-        bail!("no metadata in this user!")
+#[cfg(test)]
+mod test_list {
+    use crate::utils::test::{
+        configure, IDENTITY_GROUP_ADMIN, IDENTITY_ORG_ADMIN, IDENTITY_USER_ADMIN,
     };
-    if let Some(data) = metadata.get(data_type) {
-        info!("extracting: {data_type}");
-        let data = data
-            .as_object()
-            .ok_or_else(|| anyhow!("this should be a map!"))?;
-        if !data.is_empty() {
-            for (uuid, map) in data {
-                let val = map.get("name").ok_or_else(|| anyhow!("no name found !"))?;
-                let name = val
-                    .as_str()
-                    .ok_or_else(|| anyhow!("this should be a string!"))?;
-                projects.insert(uuid.to_owned(), name.to_owned());
-            }
-        }
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_detail_controller() {
+        let config = configure(None, None, None).await;
+        let identity = serde_json::from_str(IDENTITY_GROUP_ADMIN).unwrap();
+        let details = detail_controller(&identity, &config).await.unwrap();
+        assert_eq!(details.project, vec!["122", "334", "456"]);
+        assert_eq!(
+            details.user["af25f904-5319-4011-95a4-343365d64811"],
+            vec!["admin".to_owned()]
+        );
+        let identity = serde_json::from_str(IDENTITY_ORG_ADMIN).unwrap();
+        let details = detail_controller(&identity, &config).await.unwrap();
+        assert_eq!(
+            details.group["7113206d-afc0-41ad-bbca-b1e8113beb82"],
+            "default"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_list_project_controller() {
+        let identity = serde_json::from_str(IDENTITY_USER_ADMIN).unwrap();
+        let config = configure(None, None, None).await;
+        let grants = list_project_controller(&identity, &config).await.unwrap();
+        assert_eq!(grants.len(), 4);
+        assert_eq!(
+            grants["122"],
+            vec![Grant {
+                roles: vec!["admin".to_owned()],
+                source: Source::Group {
+                    id: "7113206d-afc0-41ad-bbca-b1e8113beb82".to_owned(),
+                    name: "awesome".to_owned()
+                }
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_list_controller() {
+        let identity = serde_json::from_str(IDENTITY_USER_ADMIN).unwrap();
+        let config = configure(None, None, None).await;
+        let groups = list_controller(identity, "group", &config).await.unwrap();
+        let group = &groups["7113206d-afc0-41ad-bbca-b1e8113beb82"];
+        assert_eq!(group.name, "awesome");
+        assert_eq!(group.role, vec!["admin".to_owned()]);
+        assert_eq!(group.project, vec!["122", "334", "456"]);
+    }
+
+    #[tokio::test]
+    async fn test_list_roles_controller() {
+        let identity = serde_json::from_str(IDENTITY_USER_ADMIN).unwrap();
+        let config = configure(None, None, None).await;
+        let roles = list_roles_controller(&identity, &config).await.unwrap();
+        assert_eq!(roles.len(), 4);
+        assert!(roles["122"].contains("admin"));
+    }
+
+    #[tokio::test]
+    async fn test_check_controller() {
+        let identity = serde_json::from_str(IDENTITY_USER_ADMIN).unwrap();
+        let config = configure(None, None, None).await;
+        assert!(check_controller(&identity, "334", "admin", &config)
+            .await
+            .unwrap());
+        assert!(!check_controller(&identity, "334", "owner", &config)
+            .await
+            .unwrap());
+        assert!(!check_controller(&identity, "999", "admin", &config)
+            .await
+            .unwrap());
     }
-    Ok(projects)
 }
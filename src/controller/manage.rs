@@ -0,0 +1,498 @@
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::Result;
+use axum::http::StatusCode;
+use ory_kratos_client::models::Identity;
+use serde::Serialize;
+use serde_json::json;
+use tracing::{error, info};
+use uuid::Uuid;
+
+use crate::{
+    config::SiriusConfig,
+    controller::{
+        list::{detail_controller, Details},
+        sync::{remove_from_iam, send_to_iam, sync, unsync_deleted, SyncMode},
+        update::get_kind_identity,
+    },
+    error::RouterError,
+    metadata::{parse_metadata, Entry, Metadata, Projects, Roles},
+    utils::kratos::{create_identity, delete_identity},
+};
+
+/// Role given to the creator of a group or an organisation.
+const CREATOR_ROLE: &str = "admin";
+
+/// Name of the group created with every organisation.
+const DEFAULT_GROUP: &str = "default";
+
+/// Structure representing the identities created.
+#[derive(Serialize, Debug)]
+pub struct Created {
+    pub id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+}
+
+/// Check if the caller hold a role allowing the action on the group or organisation.
+fn is_allowed(config: &SiriusConfig, details: &Details, caller: &Identity, action: &str) -> bool {
+    match details.user.get(&caller.id) {
+        Some(roles) => config.authorization.allows(roles, action),
+        None => false,
+    }
+}
+
+/// Check that the caller hold a role allowing the action on the group or organisation.
+fn ensure_allowed(
+    config: &SiriusConfig,
+    details: &Details,
+    caller: &Identity,
+    action: &str,
+) -> Result<()> {
+    if !is_allowed(config, details, caller, action) {
+        error!("{} is not allowed to {action} {}", caller.id, details.id);
+        Err(RouterError::Status(StatusCode::FORBIDDEN))?;
+    }
+    Ok(())
+}
+
+/// Delete an identity, logging the error if any, used to clean up a failed creation.
+async fn cleanup(config: &SiriusConfig, id: &str) {
+    if let Err(e) = delete_identity(config, id).await {
+        error!("failed to clean up identity {id}: {e}");
+    }
+}
+
+/// Undo the creation of a group, logging the errors if any: the group is removed from the
+/// given users and its organisation, then deleted.
+async fn discard_group(
+    config: &Arc<SiriusConfig>,
+    group: &str,
+    organisation: &str,
+    users: &[&str],
+) {
+    for id in users.iter().chain([&organisation]) {
+        if let Err(e) = remove_from_iam(config, id, group, "group").await {
+            error!("failed to remove group {group} from {id}: {e}");
+        }
+    }
+    cleanup(config, group).await;
+}
+
+/// Create a group identity in the organisation and make the caller admin of it.
+async fn new_group(
+    config: &Arc<SiriusConfig>,
+    caller: &Identity,
+    name: &str,
+    organisation: &str,
+) -> Result<Identity> {
+    let metadata = Metadata {
+        user: HashMap::from([(caller.id.clone(), Roles(vec![CREATOR_ROLE.to_owned()]))]),
+        parent: Some(organisation.to_owned()),
+        ..Default::default()
+    };
+    let group = create_identity(config, &config.schemas.group, name, metadata).await?;
+    info!("group {} created", group.id);
+    let entry = serde_json::to_value(Entry {
+        name: name.to_owned(),
+        ..Default::default()
+    })?;
+    if let Err(e) = send_to_iam(config, organisation, &group.id, &entry, "group").await {
+        cleanup(config, &group.id).await;
+        return Err(e);
+    }
+    let mode = SyncMode::User(vec![(caller.id.clone(), json!([CREATOR_ROLE]))]);
+    if let Err(e) = sync(config, &group.id, mode).await {
+        discard_group(config, &group.id, organisation, &[]).await;
+        return Err(e);
+    }
+    Ok(group)
+}
+
+/// Remove the group from all its users then delete it.
+async fn remove_group(config: &Arc<SiriusConfig>, group: Identity, details: Details) -> Result<()> {
+    let id = group.id.clone();
+    let users = details.user.into_keys().collect::<Vec<_>>();
+    if !users.is_empty() {
+        info!("removing group {id} from its users");
+        unsync_deleted(config, &id, users).await?;
+    }
+    delete_identity(config, &id).await?;
+    config.index.remove(&id);
+    Ok(())
+}
+
+/// Create an organisation with its default group, the caller is admin of both.
+/// Only the creators allowed by the config can create an organisation.
+pub async fn create_organisation_controller(
+    config: Arc<SiriusConfig>,
+    caller: &Identity,
+    name: &str,
+) -> Result<Created> {
+    if !config.organisations.can_create(&caller.id) {
+        error!("{} is not allowed to create organisations", caller.id);
+        Err(RouterError::Status(StatusCode::FORBIDDEN))?;
+    }
+    let metadata = Metadata {
+        project: Projects::Roles(HashMap::new()),
+        user: HashMap::from([(caller.id.clone(), Roles(vec![CREATOR_ROLE.to_owned()]))]),
+        ..Default::default()
+    };
+    let organisation =
+        create_identity(&config, &config.schemas.organisation, name, metadata).await?;
+    info!("organisation {} created", organisation.id);
+    let id = organisation.id;
+    let group = match new_group(&config, caller, DEFAULT_GROUP, &id).await {
+        Ok(group) => group,
+        Err(e) => {
+            cleanup(&config, &id).await;
+            return Err(e);
+        }
+    };
+    let mode = SyncMode::User(vec![(caller.id.clone(), json!([CREATOR_ROLE]))]);
+    if let Err(e) = sync(&config, &id, mode).await {
+        discard_group(&config, &group.id, &id, &[&caller.id]).await;
+        cleanup(&config, &id).await;
+        return Err(e);
+    }
+    Ok(Created {
+        id,
+        group: Some(group.id),
+    })
+}
+
+/// Create a group in an organisation, the caller must be admin of the organisation.
+pub async fn create_group_controller(
+    config: Arc<SiriusConfig>,
+    caller: &Identity,
+    name: &str,
+    organisation: Uuid,
+) -> Result<Created> {
+    if name == DEFAULT_GROUP {
+        error!("the default group is created with the organisation");
+        Err(RouterError::Status(StatusCode::CONFLICT))?;
+    }
+    let organisation = get_kind_identity(&config, organisation, "organisation").await?;
+    let details = detail_controller(&organisation, &config).await?;
+    ensure_allowed(&config, &details, caller, "post")?;
+    let group = new_group(&config, caller, name, &organisation.id).await?;
+    Ok(Created {
+        id: group.id,
+        group: None,
+    })
+}
+
+/// Delete a group and remove it from its users and organisation, the caller must be allowed
+/// to delete the group or its organisation. An identity which is not a group is not found.
+pub async fn delete_group_controller(
+    config: Arc<SiriusConfig>,
+    caller: &Identity,
+    id: Uuid,
+) -> Result<()> {
+    let group = get_kind_identity(&config, id, "group").await?;
+    let details = detail_controller(&group, &config).await?;
+    let parent = parse_metadata(&config, &group)?.parent;
+    match &parent {
+        Some(parent) if !is_allowed(&config, &details, caller, "delete") => {
+            let parent = Uuid::parse_str(parent)?;
+            let organisation = get_kind_identity(&config, parent, "organisation").await?;
+            let organisation = detail_controller(&organisation, &config).await?;
+            ensure_allowed(&config, &organisation, caller, "delete")?;
+        }
+        _ => ensure_allowed(&config, &details, caller, "delete")?,
+    }
+    if parent.is_some() && details.name == json!(DEFAULT_GROUP) {
+        error!("the default group is deleted with the organisation");
+        Err(RouterError::Status(StatusCode::CONFLICT))?;
+    }
+    remove_group(&config, group, details).await?;
+    if let Some(parent) = parent {
+        info!("removing group {id} from organisation {parent}");
+        remove_from_iam(&config, &parent, &id.to_string(), "group").await?;
+    }
+    Ok(())
+}
+
+/// Delete an organisation with all its groups and remove them from their users.
+/// An identity which is not an organisation is not found.
+pub async fn delete_organisation_controller(
+    config: Arc<SiriusConfig>,
+    caller: &Identity,
+    id: Uuid,
+) -> Result<()> {
+    let organisation = get_kind_identity(&config, id, "organisation").await?;
+    let details = detail_controller(&organisation, &config).await?;
+    ensure_allowed(&config, &details, caller, "delete")?;
+    for group_id in details.group.keys() {
+        let group_id = Uuid::parse_str(group_id)?;
+        let group = get_kind_identity(&config, group_id, "group").await?;
+        let group_details = detail_controller(&group, &config).await?;
+        info!("deleting group {group_id}");
+        remove_group(&config, group, group_details).await?;
+    }
+    let users = details.user.into_keys().collect::<Vec<_>>();
+    if !users.is_empty() {
+        info!("removing organisation {id} from its users");
+        unsync_deleted(&config, &id.to_string(), users).await?;
+    }
+    delete_identity(&config, &id.to_string()).await?;
+    config.index.remove(&id.to_string());
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_manage {
+    use mockito::Server as MockServer;
+
+    use super::*;
+    use crate::utils::test::{
+        configure, IDENTITY_GROUP_ADMIN, IDENTITY_ORG_ADMIN, IDENTITY_USER_ADMIN,
+    };
+
+    /// Id of the identities of the fixtures, read back by the syncs.
+    const CREATED_ID: &str = "af25f904-5319-4011-95a4-343365d64811";
+
+    /// Set the kratos schema of a fixture.
+    fn with_schema(fixture: &str, schema: &str) -> String {
+        let mut identity: serde_json::Value = serde_json::from_str(fixture).unwrap();
+        identity["schema_id"] = json!(schema);
+        identity.to_string()
+    }
+
+    #[tokio::test]
+    async fn test_create_organisation_controller() {
+        let mut kratos_server = MockServer::new_async().await;
+        let mut config = configure(Some(&kratos_server), None, None).await;
+        config.organisations.creators = vec![CREATED_ID.to_owned()];
+        let mock_create = kratos_server
+            .mock("POST", "/admin/identities")
+            .with_status(201)
+            .with_header("content-type", "application/json")
+            .with_body(IDENTITY_GROUP_ADMIN)
+            .expect(2)
+            .create_async()
+            .await;
+        let mock_read = kratos_server
+            .mock("GET", format!("/admin/identities/{CREATED_ID}").as_str())
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(IDENTITY_GROUP_ADMIN)
+            .expect_at_least(2)
+            .create_async()
+            .await;
+        let caller = serde_json::from_str(IDENTITY_USER_ADMIN).unwrap();
+        let created = create_organisation_controller(Arc::new(config), &caller, "awesome")
+            .await
+            .unwrap();
+        assert!(created.group.is_some());
+        mock_create.assert_async().await;
+        mock_read.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_delete_organisation_controller() {
+        let mut kratos_server = MockServer::new_async().await;
+        let config = configure(Some(&kratos_server), None, None).await;
+        let org_id = "9f425a8d-7efc-4768-8f23-7647a74fdf13";
+        let group_id = "7113206d-afc0-41ad-bbca-b1e8113beb82";
+        let mock_org = kratos_server
+            .mock("GET", format!("/admin/identities/{org_id}").as_str())
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(with_schema(IDENTITY_ORG_ADMIN, "organisation"))
+            .expect(2)
+            .create_async()
+            .await;
+        let mock_group = kratos_server
+            .mock("GET", format!("/admin/identities/{group_id}").as_str())
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(with_schema(IDENTITY_GROUP_ADMIN, "group"))
+            .create_async()
+            .await;
+        let mock_read = kratos_server
+            .mock("GET", format!("/admin/identities/{CREATED_ID}").as_str())
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(IDENTITY_GROUP_ADMIN)
+            .expect_at_least(1)
+            .create_async()
+            .await;
+        let mock_delete = kratos_server
+            .mock("DELETE", mockito::Matcher::Any)
+            .with_status(204)
+            .expect(2)
+            .create_async()
+            .await;
+        let caller = serde_json::from_str(IDENTITY_USER_ADMIN).unwrap();
+        delete_organisation_controller(Arc::new(config), &caller, Uuid::parse_str(org_id).unwrap())
+            .await
+            .unwrap();
+        mock_org.assert_async().await;
+        mock_group.assert_async().await;
+        mock_read.assert_async().await;
+        mock_delete.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_create_organisation_forbidden() {
+        let config = configure(None, None, None).await;
+        let caller = serde_json::from_str(IDENTITY_USER_ADMIN).unwrap();
+        let error = create_organisation_controller(Arc::new(config), &caller, "awesome")
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            RouterError::from(error),
+            RouterError::Status(StatusCode::FORBIDDEN)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_create_organisation_cleanup() {
+        let mut kratos_server = MockServer::new_async().await;
+        let mut config = configure(Some(&kratos_server), None, None).await;
+        config.organisations.open = true;
+        let org_id = "9f425a8d-7efc-4768-8f23-7647a74fdf13";
+        let mut organisation: serde_json::Value = serde_json::from_str(IDENTITY_ORG_ADMIN).unwrap();
+        organisation["id"] = json!(org_id);
+        let mock_org = kratos_server
+            .mock("POST", "/admin/identities")
+            .match_body(mockito::Matcher::PartialJson(
+                json!({"schema_id": "organisation"}),
+            ))
+            .with_status(201)
+            .with_header("content-type", "application/json")
+            .with_body(organisation.to_string())
+            .create_async()
+            .await;
+        let mock_group = kratos_server
+            .mock("POST", "/admin/identities")
+            .match_body(mockito::Matcher::PartialJson(json!({"schema_id": "group"})))
+            .with_status(201)
+            .with_header("content-type", "application/json")
+            .with_body(IDENTITY_GROUP_ADMIN)
+            .create_async()
+            .await;
+        // the group is synced but the organisation can not be read back by its sync
+        let mock_read = kratos_server
+            .mock("GET", format!("/admin/identities/{CREATED_ID}").as_str())
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(IDENTITY_GROUP_ADMIN)
+            .expect_at_least(1)
+            .create_async()
+            .await;
+        let mock_delete = kratos_server
+            .mock("DELETE", format!("/admin/identities/{CREATED_ID}").as_str())
+            .with_status(204)
+            .create_async()
+            .await;
+        let mock_delete_org = kratos_server
+            .mock("DELETE", format!("/admin/identities/{org_id}").as_str())
+            .with_status(204)
+            .create_async()
+            .await;
+        let caller = serde_json::from_str(IDENTITY_USER_ADMIN).unwrap();
+        let created = create_organisation_controller(Arc::new(config), &caller, "awesome").await;
+        assert!(created.is_err());
+        mock_org.assert_async().await;
+        mock_group.assert_async().await;
+        mock_read.assert_async().await;
+        mock_delete.assert_async().await;
+        mock_delete_org.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_delete_group_organisation_admin() {
+        let mut kratos_server = MockServer::new_async().await;
+        let config = configure(Some(&kratos_server), None, None).await;
+        let org_id = "9f425a8d-7efc-4768-8f23-7647a74fdf13";
+        let group_id = "7113206d-afc0-41ad-bbca-b1e8113beb82";
+        let mut group: serde_json::Value = serde_json::from_str(IDENTITY_GROUP_ADMIN).unwrap();
+        group["id"] = json!(group_id);
+        group["schema_id"] = json!("group");
+        group["metadata_public"] = json!({"project": [], "parent": org_id});
+        let mock_group = kratos_server
+            .mock("GET", format!("/admin/identities/{group_id}").as_str())
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(group.to_string())
+            .create_async()
+            .await;
+        let mock_org = kratos_server
+            .mock("GET", format!("/admin/identities/{org_id}").as_str())
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(with_schema(IDENTITY_ORG_ADMIN, "organisation"))
+            .create_async()
+            .await;
+        let mock_delete = kratos_server
+            .mock("DELETE", format!("/admin/identities/{group_id}").as_str())
+            .with_status(204)
+            .create_async()
+            .await;
+        let caller = serde_json::from_str(IDENTITY_USER_ADMIN).unwrap();
+        let id = Uuid::parse_str(group_id).unwrap();
+        delete_group_controller(Arc::new(config), &caller, id)
+            .await
+            .unwrap();
+        mock_group.assert_async().await;
+        mock_org.assert_async().await;
+        mock_delete.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_delete_other_kind() {
+        let mut kratos_server = MockServer::new_async().await;
+        let config = Arc::new(configure(Some(&kratos_server), None, None).await);
+        let org_id = "9f425a8d-7efc-4768-8f23-7647a74fdf13";
+        let group_id = "7113206d-afc0-41ad-bbca-b1e8113beb82";
+        let mock_org = kratos_server
+            .mock("GET", format!("/admin/identities/{org_id}").as_str())
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(with_schema(IDENTITY_ORG_ADMIN, "organisation"))
+            .create_async()
+            .await;
+        let mock_group = kratos_server
+            .mock("GET", format!("/admin/identities/{group_id}").as_str())
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(with_schema(IDENTITY_GROUP_ADMIN, "group"))
+            .create_async()
+            .await;
+        let mock_delete = kratos_server
+            .mock("DELETE", mockito::Matcher::Any)
+            .expect(0)
+            .create_async()
+            .await;
+        let caller = serde_json::from_str(IDENTITY_USER_ADMIN).unwrap();
+        let not_found = |error: anyhow::Error| {
+            matches!(
+                RouterError::from(error),
+                RouterError::Status(StatusCode::NOT_FOUND)
+            )
+        };
+        let org = Uuid::parse_str(org_id).unwrap();
+        let error = delete_group_controller(config.clone(), &caller, org)
+            .await
+            .unwrap_err();
+        assert!(not_found(error));
+        let group = Uuid::parse_str(group_id).unwrap();
+        let error = delete_organisation_controller(config, &caller, group)
+            .await
+            .unwrap_err();
+        assert!(not_found(error));
+        mock_org.assert_async().await;
+        mock_group.assert_async().await;
+        mock_delete.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_create_default_group() {
+        let config = configure(None, None, None).await;
+        let caller = serde_json::from_str(IDENTITY_USER_ADMIN).unwrap();
+        let res = create_group_controller(Arc::new(config), &caller, "default", Uuid::nil()).await;
+        assert!(res.is_err());
+    }
+}
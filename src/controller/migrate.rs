@@ -0,0 +1,271 @@
+use std::{collections::HashMap, fmt, sync::Arc};
+
+use anyhow::{anyhow, Result};
+use ory_kratos_client::models::Identity;
+use serde::Serialize;
+use serde_json::{json, Value};
+use tracing::{error, info};
+
+use crate::{
+    config::SiriusConfig,
+    controller::sync::send_to_iam,
+    metadata::{parse_metadata, raw_metadata, METADATA_VERSION},
+    utils::kratos::list_all_identities,
+};
+
+/// Permission types rewritten by the migration.
+const PERM_TYPES: [&str; 4] = ["project", "group", "organisation", "user"];
+
+/// Structure representing an entry of the metadata to rewrite.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Change {
+    pub perm_type: String,
+    pub resource: String,
+    pub value: Value,
+}
+
+/// Enum representing the outcome of the migration of an identity.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum Status {
+    Unchanged,
+    Planned,
+    Migrated,
+    Failed { error: String },
+}
+
+/// Structure representing the migration report of an identity.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Migration {
+    pub id: String,
+    pub changes: Vec<Change>,
+    #[serde(flatten)]
+    pub status: Status,
+}
+
+impl fmt::Display for Migration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = match &self.status {
+            Status::Unchanged => "unchanged".to_owned(),
+            Status::Planned => "to migrate".to_owned(),
+            Status::Migrated => "migrated".to_owned(),
+            Status::Failed { error } => format!("failed: {error}"),
+        };
+        write!(f, "{}: {status}", self.id)?;
+        for change in &self.changes {
+            match change.resource.is_empty() {
+                true => write!(f, "\n  {} => {}", change.perm_type, change.value)?,
+                false => write!(
+                    f,
+                    "\n  {}.{} => {}",
+                    change.perm_type, change.resource, change.value
+                )?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Compute the entries of the metadata whose stored value differ from the canonical one.
+/// The projects are stored as a map of their roles, a list of projects is replaced by this map
+/// as a whole, in a change without resource. The identity is then stamped with the version of
+/// the metadata, also a change without resource.
+fn plan(config: &SiriusConfig, identity: &Identity) -> Result<Vec<Change>> {
+    let metadata = parse_metadata(config, identity)?;
+    let raw = raw_metadata(config, identity)?.unwrap_or(&Value::Null);
+    let mut canonical = serde_json::to_value(&metadata)?;
+    let projects: HashMap<_, _> = metadata.project.roles().into_iter().collect();
+    canonical["project"] = serde_json::to_value(projects)?;
+    let mut changes = Vec::new();
+    for perm_type in PERM_TYPES {
+        let stored = raw.get(perm_type);
+        let Some(Value::Object(entries)) = canonical.get(perm_type) else {
+            continue;
+        };
+        if stored.is_some_and(|stored| !stored.is_object() && !stored.is_null()) {
+            changes.push(Change {
+                perm_type: perm_type.to_owned(),
+                resource: String::new(),
+                value: Value::Object(entries.clone()),
+            });
+            continue;
+        }
+        for (resource, value) in entries {
+            if stored.and_then(|stored| stored.get(resource)) != Some(value) {
+                changes.push(Change {
+                    perm_type: perm_type.to_owned(),
+                    resource: resource.to_owned(),
+                    value: value.clone(),
+                });
+            }
+        }
+    }
+    if raw.get("version") != Some(&json!(METADATA_VERSION)) {
+        changes.push(Change {
+            perm_type: "version".to_owned(),
+            resource: String::new(),
+            value: json!(METADATA_VERSION),
+        });
+    }
+    Ok(changes)
+}
+
+/// Rewrite the metadata of an identity in its canonical shape.
+/// The changes of a whole field need an iam server replacing it on an empty resource, without
+/// `iam.whole_field` the identity is reported as failed and nothing is written.
+async fn migrate_identity(
+    config: &Arc<SiriusConfig>,
+    identity: &Identity,
+    dry_run: bool,
+) -> Migration {
+    let changes = match plan(config, identity) {
+        Ok(changes) => changes,
+        Err(e) => {
+            return Migration {
+                id: identity.id.clone(),
+                changes: Vec::new(),
+                status: Status::Failed {
+                    error: e.to_string(),
+                },
+            }
+        }
+    };
+    let whole = changes.iter().any(|change| change.resource.is_empty());
+    let mut status = match (changes.is_empty(), dry_run) {
+        (true, _) => Status::Unchanged,
+        (false, _) if whole && !config.iam.whole_field => Status::Failed {
+            error: "rewriting a whole field requires iam.whole_field".to_owned(),
+        },
+        (false, true) => Status::Planned,
+        (false, false) => Status::Migrated,
+    };
+    if status == Status::Migrated {
+        for change in &changes {
+            let sent = send_to_iam(
+                config,
+                &identity.id,
+                &change.resource,
+                &change.value,
+                &change.perm_type,
+            )
+            .await;
+            if let Err(e) = sent {
+                error!("failed to migrate identity {}: {e}", identity.id);
+                status = Status::Failed {
+                    error: e.to_string(),
+                };
+                break;
+            }
+        }
+    }
+    Migration {
+        id: identity.id.clone(),
+        changes,
+        status,
+    }
+}
+
+/// Page through all the kratos identities and rewrite their metadata in the canonical shape.
+/// Nothing is written in dry run mode.
+pub async fn migrate_controller(
+    config: Arc<SiriusConfig>,
+    dry_run: bool,
+) -> Result<Vec<Migration>> {
+    info!("migrating the identities metadata, dry run: {dry_run}");
+    let identities = list_all_identities(&config)
+        .await
+        .map_err(|e| anyhow!("failed to list identities: {e}"))?;
+    let mut migrations = Vec::new();
+    for identity in &identities {
+        migrations.push(migrate_identity(&config, identity, dry_run).await);
+    }
+    Ok(migrations)
+}
+
+#[cfg(test)]
+mod test_migrate {
+    use mockito::Server as MockServer;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::utils::test::{
+        configure, IDENTITY_GROUP_ADMIN, IDENTITY_ORG_ADMIN, IDENTITY_USER_ADMIN,
+    };
+
+    #[tokio::test]
+    async fn test_plan() {
+        let config = configure(None, None, None).await;
+        let group = serde_json::from_str(IDENTITY_GROUP_ADMIN).unwrap();
+        let changes = plan(&config, &group).unwrap();
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[0].perm_type, "project");
+        assert!(changes[0].resource.is_empty());
+        assert_eq!(changes[0].value, json!({"122": [], "334": [], "456": []}));
+        // the identity is stamped once migrated
+        assert_eq!(changes[1].perm_type, "version");
+        assert!(changes[1].resource.is_empty());
+        assert_eq!(changes[1].value, json!(METADATA_VERSION));
+        let user = serde_json::from_str(IDENTITY_USER_ADMIN).unwrap();
+        let changes = plan(&config, &user).unwrap();
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[0].resource, "7113206d-afc0-41ad-bbca-b1e8113beb82");
+        assert_eq!(changes[0].value["project"], json!(["122", "334", "456"]));
+    }
+
+    /// Write the changes in the metadata of an identity like iam.
+    fn apply(identity: &mut Identity, changes: &[Change]) {
+        let raw = identity.metadata_public.as_mut().unwrap();
+        for change in changes {
+            match change.resource.is_empty() {
+                true => raw[&change.perm_type] = change.value.clone(),
+                false => raw[&change.perm_type][&change.resource] = change.value.clone(),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_plan_migrated() {
+        let config = configure(None, None, None).await;
+        for fixture in [
+            IDENTITY_GROUP_ADMIN,
+            IDENTITY_USER_ADMIN,
+            IDENTITY_ORG_ADMIN,
+        ] {
+            let mut identity = serde_json::from_str(fixture).unwrap();
+            let changes = plan(&config, &identity).unwrap();
+            apply(&mut identity, &changes);
+            assert!(plan(&config, &identity).unwrap().is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_migrate_controller() {
+        let mut kratos_server = MockServer::new_async().await;
+        let mut config = configure(Some(&kratos_server), None, None).await;
+        let body = format!("[{IDENTITY_USER_ADMIN}, {IDENTITY_GROUP_ADMIN}]");
+        let mock_kratos = kratos_server
+            .mock("GET", "/admin/identities?page=1&per_page=250")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(body)
+            .expect(3)
+            .create_async()
+            .await;
+        // the whole fields are not rewritten by an iam server which does not replace them
+        let migrations = migrate_controller(Arc::new(config.clone()), false)
+            .await
+            .unwrap();
+        assert!(migrations
+            .iter()
+            .all(|m| matches!(m.status, Status::Failed { .. })));
+        config.iam.whole_field = true;
+        let config = Arc::new(config);
+        let migrations = migrate_controller(config.clone(), true).await.unwrap();
+        assert_eq!(migrations.len(), 2);
+        assert!(migrations.iter().all(|m| m.status == Status::Planned));
+        let migrations = migrate_controller(config, false).await.unwrap();
+        assert!(migrations.iter().all(|m| m.status == Status::Migrated));
+        mock_kratos.assert_async().await;
+    }
+}
@@ -1,4 +1,8 @@
+pub mod diff;
 pub mod list;
+pub mod manage;
+pub mod migrate;
+pub mod reconcile;
 pub mod sync;
 pub mod update;
 
@@ -0,0 +1,690 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Result};
+use ory_kratos_client::models::Identity;
+use serde::Serialize;
+use serde_json::{json, Value};
+use stream_cancel::Tripwire;
+use tokio::task::JoinSet;
+use tracing::{error, info, warn};
+
+use crate::{
+    config::SiriusConfig,
+    controller::sync::{send_patch, Patch},
+    metadata::{parse_metadata, Entry, Metadata, Roles},
+    utils::{
+        authz::ensure_admin_kept,
+        error::send_error,
+        kafka::send_to_kafka,
+        kratos::{list_all_identities, read_identity},
+    },
+    ConfigState,
+};
+
+/// Enum representing how the copy of a group in the metadata of a user diverged.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DriftKind {
+    /// The user is member of the group but has no copy of it.
+    Missing,
+    /// The copy held by the user differ from the group.
+    Outdated,
+    /// The user hold a copy of a group it is not member of.
+    Stale,
+}
+
+/// Structure representing a divergence between a group or organisation and one of its users.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Drift {
+    pub group: String,
+    pub user: String,
+    pub kind: DriftKind,
+    pub repaired: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl fmt::Display for Drift {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} in {}: {:?}", self.user, self.group, self.kind)?;
+        match (&self.error, self.repaired) {
+            (Some(error), _) => write!(f, " (repair failed: {error})"),
+            (None, true) => write!(f, " (repaired)"),
+            (None, false) => Ok(()),
+        }
+    }
+}
+
+/// Structure representing a group or organisation as its users should see it.
+struct Expected<'a> {
+    id: &'a str,
+    name: Value,
+    projects: Vec<String>,
+    users: &'a HashMap<String, Roles>,
+}
+
+/// Get a group or organisation as its users should see it.
+fn expected_of<'a>(identity: &'a Identity, metadata: &'a Metadata) -> Expected<'a> {
+    let name = identity
+        .traits
+        .as_ref()
+        .and_then(|traits| traits.get("name"))
+        .cloned()
+        .unwrap_or(Value::Null);
+    Expected {
+        id: &identity.id,
+        name,
+        projects: metadata.project.ids(),
+        users: &metadata.user,
+    }
+}
+
+/// Check if the identity is a group or an organisation.
+fn is_group(config: &SiriusConfig, identity: &Identity, metadata: &Metadata) -> bool {
+    identity.schema_id == config.schemas.group
+        || identity.schema_id == config.schemas.organisation
+        || !metadata.user.is_empty()
+}
+
+/// Check if the copy of the group held by a user match the group.
+fn matches(entry: &Entry, expected: &Expected, roles: &[String]) -> bool {
+    let mut projects = entry.project.ids();
+    projects.sort();
+    let mut wanted_projects = expected.projects.clone();
+    wanted_projects.sort();
+    let mut held = entry.role.0.clone();
+    held.sort();
+    let mut wanted_roles = roles.to_vec();
+    wanted_roles.sort();
+    Some(entry.name.as_str()) == expected.name.as_str()
+        && projects == wanted_projects
+        && held == wanted_roles
+}
+
+/// Find how the copy of a group held by an identity diverged from the group.
+/// The groups and organisations are not checked for stale copies, the organisations list their
+/// groups without being member of them.
+fn drift_kind(
+    expected: &Expected,
+    user: &str,
+    metadata: &Metadata,
+    groups: &HashSet<String>,
+) -> Option<DriftKind> {
+    match (expected.users.get(user), metadata.group.get(expected.id)) {
+        (Some(_), None) => Some(DriftKind::Missing),
+        (Some(roles), Some(entry)) if !matches(entry, expected, &roles.0) => {
+            Some(DriftKind::Outdated)
+        }
+        (None, Some(_)) if !groups.contains(user) => Some(DriftKind::Stale),
+        _ => None,
+    }
+}
+
+/// Compare a group with the copies held by all the identities.
+fn compare(
+    expected: &Expected,
+    metadatas: &HashMap<String, Metadata>,
+    groups: &HashSet<String>,
+) -> Vec<Drift> {
+    let mut drifts = Vec::new();
+    let mut push = |user: &str, metadata: &Metadata| {
+        if let Some(kind) = drift_kind(expected, user, metadata, groups) {
+            drifts.push(Drift {
+                group: expected.id.to_owned(),
+                user: user.to_owned(),
+                kind,
+                repaired: false,
+                error: None,
+            });
+        }
+    };
+    for user in expected.users.keys() {
+        match metadatas.get(user) {
+            Some(metadata) => push(user, metadata),
+            None => warn!("user {user} of {} not found", expected.id),
+        }
+    }
+    for (user, metadata) in metadatas {
+        if !expected.users.contains_key(user) {
+            push(user, metadata);
+        }
+    }
+    drifts
+}
+
+/// Replay the permission call bringing the copy of the group held by the user back in sync.
+/// The caller holds the lock of the group and read it again, the user is read again under its
+/// lock and left alone if its copy no longer diverges. Return whether the copy was written.
+async fn repair(
+    config: &Arc<SiriusConfig>,
+    expected: &Expected<'_>,
+    groups: &HashSet<String>,
+    blocked: Option<&str>,
+    drift: &mut Drift,
+) -> Result<bool> {
+    let _guard = config.locks.lock(&drift.user).await?;
+    let user = read_identity(config, &drift.user).await?;
+    let metadata = parse_metadata(config, &user)?;
+    let Some(kind) = drift_kind(expected, &drift.user, &metadata, groups) else {
+        info!("{} in {} was brought back in sync", drift.user, drift.group);
+        return Ok(false);
+    };
+    drift.kind = kind;
+    if let (Some(e), DriftKind::Outdated | DriftKind::Stale) = (blocked, kind) {
+        bail!("{e}");
+    }
+    let value = match kind {
+        DriftKind::Missing | DriftKind::Outdated => {
+            let role = expected
+                .users
+                .get(&drift.user)
+                .map(|roles| roles.0.clone())
+                .unwrap_or_default();
+            Some(json!({
+                "name": expected.name,
+                "project": expected.projects,
+                "role": role
+            }))
+        }
+        DriftKind::Stale => None,
+    };
+    let patch = Patch {
+        id: drift.user.clone(),
+        perm_type: "group".to_owned(),
+        resource: expected.id.to_owned(),
+        value,
+    };
+    send_patch(config, &patch, None).await?;
+    Ok(true)
+}
+
+/// Repair the drifts of a group under its lock, from the group read again.
+async fn repair_group(
+    config: &Arc<SiriusConfig>,
+    id: &str,
+    snapshot: &Snapshot,
+    drifts: &mut [Drift],
+) -> Result<()> {
+    let _guard = config.locks.lock(id).await?;
+    let group = read_identity(config, id).await?;
+    let metadata = parse_metadata(config, &group)?;
+    let expected = expected_of(&group, &metadata);
+    let blocked = ensure_copies_admin(config, &expected, &snapshot.metadatas)
+        .err()
+        .map(|e| e.to_string());
+    for drift in drifts {
+        match repair(
+            config,
+            &expected,
+            &snapshot.groups,
+            blocked.as_deref(),
+            drift,
+        )
+        .await
+        {
+            Ok(repaired) => drift.repaired = repaired,
+            Err(e) => {
+                error!("failed to repair {} in {}: {e}", drift.user, drift.group);
+                drift.error = Some(e.to_string());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Structure holding the identities, their parsed metadata and the ids of the groups and
+/// organisations.
+struct Snapshot {
+    identities: Vec<Identity>,
+    metadatas: HashMap<String, Metadata>,
+    groups: HashSet<String>,
+}
+
+impl Snapshot {
+    /// Fetch all the identities from kratos and parse their metadata.
+    async fn fetch(config: &SiriusConfig) -> Result<Self> {
+        let identities = list_all_identities(config)
+            .await
+            .map_err(|e| anyhow!("failed to list identities: {e}"))?;
+        let mut metadatas = HashMap::new();
+        let mut groups = HashSet::new();
+        for identity in &identities {
+            match parse_metadata(config, identity) {
+                Ok(metadata) => {
+                    if is_group(config, identity, &metadata) {
+                        groups.insert(identity.id.clone());
+                    }
+                    metadatas.insert(identity.id.clone(), metadata);
+                }
+                Err(e) => warn!("skipping identity {}: {e}", identity.id),
+            }
+        }
+        Ok(Snapshot {
+            identities,
+            metadatas,
+            groups,
+        })
+    }
+
+    /// List the groups and organisations sorted by id.
+    fn groups(&self) -> Vec<Identity> {
+        let mut groups = self
+            .identities
+            .iter()
+            .filter(|identity| self.groups.contains(&identity.id))
+            .cloned()
+            .collect::<Vec<_>>();
+        groups.sort_by(|a, b| a.id.cmp(&b.id));
+        groups
+    }
+
+    /// List the organisations sorted by id with their groups, and the groups outside any
+    /// organisation.
+    fn organisations(&self, config: &SiriusConfig) -> (Vec<Vec<Identity>>, Vec<Identity>) {
+        let groups = self.groups();
+        let parent = |group: &Identity| -> Option<String> {
+            let metadata = self.metadatas.get(&group.id)?;
+            if let Some(parent) = &metadata.parent {
+                return Some(parent.clone());
+            }
+            self.metadatas
+                .iter()
+                .find(|(id, metadata)| {
+                    self.groups.contains(*id) && metadata.group.contains_key(&group.id)
+                })
+                .map(|(id, _)| id.clone())
+        };
+        let (organisations, groups): (Vec<_>, Vec<_>) = groups
+            .into_iter()
+            .partition(|identity| identity.schema_id == config.schemas.organisation);
+        let mut batches: Vec<Vec<Identity>> = organisations
+            .into_iter()
+            .map(|organisation| vec![organisation])
+            .collect();
+        let mut orphans = Vec::new();
+        for group in groups {
+            let batch = parent(&group)
+                .and_then(|parent| batches.iter_mut().find(|batch| batch[0].id == parent));
+            match batch {
+                Some(batch) => batch.push(group),
+                None => orphans.push(group),
+            }
+        }
+        (batches, orphans)
+    }
+}
+
+/// Compare a group or organisation with the copies held by the users and repair the drifts if
+/// asked, from the group and the users read again under their locks.
+async fn reconcile_group(
+    config: Arc<SiriusConfig>,
+    identity: Identity,
+    snapshot: Arc<Snapshot>,
+    fix: bool,
+) -> Vec<Drift> {
+    let Some(metadata) = snapshot.metadatas.get(&identity.id) else {
+        return Vec::new();
+    };
+    let expected = expected_of(&identity, metadata);
+    let mut drifts = compare(&expected, &snapshot.metadatas, &snapshot.groups);
+    if fix && !drifts.is_empty() {
+        if let Err(e) = repair_group(&config, &identity.id, &snapshot, &mut drifts).await {
+            error!("failed to repair the copies of {}: {e}", identity.id);
+            for drift in &mut drifts {
+                drift.error = Some(e.to_string());
+            }
+        }
+    }
+    drifts
+}
+
+/// Check that repairing the copies of a group keeps an admin, the users rely on the roles of
+/// their copy so a group whose metadata lost its admins must not lose them in the copies.
+fn ensure_copies_admin(
+    config: &SiriusConfig,
+    expected: &Expected,
+    metadatas: &HashMap<String, Metadata>,
+) -> Result<()> {
+    let copies = metadatas
+        .iter()
+        .filter_map(|(user, metadata)| {
+            let entry = metadata.group.get(expected.id)?;
+            Some((user.clone(), entry.role.0.clone()))
+        })
+        .collect();
+    let users = expected
+        .users
+        .iter()
+        .map(|(user, roles)| (user.clone(), roles.0.clone()))
+        .collect();
+    ensure_admin_kept(config, expected.id, &copies, &users)
+}
+
+/// Reconcile the groups, `concurrency` of them at a time.
+async fn reconcile_groups(
+    config: &Arc<SiriusConfig>,
+    groups: Vec<Identity>,
+    snapshot: Arc<Snapshot>,
+    fix: bool,
+) -> Result<Vec<Drift>> {
+    let count = groups.len();
+    let concurrency = config.reconcile.concurrency.max(1);
+    let mut handles = JoinSet::new();
+    let mut drifts = Vec::new();
+    for identity in groups {
+        if handles.len() >= concurrency {
+            if let Some(found) = handles.join_next().await {
+                drifts.extend(found?);
+            }
+        }
+        handles.spawn(reconcile_group(
+            config.clone(),
+            identity,
+            snapshot.clone(),
+            fix,
+        ));
+    }
+    while let Some(found) = handles.join_next().await {
+        drifts.extend(found?);
+    }
+    info!("{} drifts found in {count} groups", drifts.len());
+    Ok(drifts)
+}
+
+/// Compare the groups and organisations, or only the given one, with the copies held by their
+/// users and report the drifts. The drifts are repaired if asked.
+pub async fn reconcile_controller(
+    config: Arc<SiriusConfig>,
+    id: Option<&str>,
+    fix: bool,
+) -> Result<Vec<Drift>> {
+    info!("reconciling groups, repair: {fix}");
+    let snapshot = Snapshot::fetch(&config).await?;
+    let mut groups = snapshot.groups();
+    if let Some(id) = id {
+        groups.retain(|identity| identity.id == id);
+        if groups.is_empty() {
+            bail!("group or organisation {id} not found");
+        }
+    }
+    reconcile_groups(&config, groups, Arc::new(snapshot), fix).await
+}
+
+/// Reconcile at most the budget of organisations of the config with their groups, starting at
+/// the given offset in the sorted organisations. The groups outside any organisation are
+/// reconciled with the first batch. Return the drifts and the offset of the next batch, which
+/// wrap around once all the organisations were processed.
+pub async fn reconcile_batch(
+    config: Arc<SiriusConfig>,
+    offset: usize,
+) -> Result<(Vec<Drift>, usize)> {
+    let snapshot = Snapshot::fetch(&config).await?;
+    let (organisations, orphans) = snapshot.organisations(&config);
+    let total = organisations.len();
+    let offset = if offset >= total { 0 } else { offset };
+    let end = total.min(offset + config.reconcile.budget.max(1));
+    info!("reconciling organisations {offset} to {end} of {total}");
+    let mut batch = Vec::new();
+    if offset == 0 {
+        batch.extend(orphans);
+    }
+    for groups in organisations.into_iter().take(end).skip(offset) {
+        batch.extend(groups);
+    }
+    let fix = config.reconcile.repair;
+    let drifts = reconcile_groups(&config, batch, Arc::new(snapshot), fix).await?;
+    let next = if end >= total { 0 } else { end };
+    Ok((drifts, next))
+}
+
+/// Structure representing the result of a periodic reconciliation sent to kafka.
+#[derive(Serialize, Debug)]
+struct Report {
+    found: usize,
+    repaired: usize,
+    failed: usize,
+    drifts: Vec<Drift>,
+}
+
+/// Reconcile a batch of organisations and their groups every interval of the config until the
+/// tripwire is triggered, the results are sent to the notif topic.
+#[cfg(not(tarpaulin_include))]
+pub async fn reconcile_loop(state: ConfigState, shutdown: Tripwire) {
+    let mut offset = 0;
+    loop {
+        let interval = state.read().await.reconcile.interval.max(1);
+        tokio::select! {
+            () = tokio::time::sleep(Duration::from_secs(interval)) => (),
+            _ = shutdown.clone() => break,
+        }
+        let config = Arc::new(state.read().await.clone());
+        if !config.reconcile.enabled {
+            continue;
+        }
+        let result = tokio::select! {
+            result = reconcile_batch(config.clone(), offset) => result,
+            _ = shutdown.clone() => break,
+        };
+        match result {
+            Ok((drifts, next)) => {
+                offset = next;
+                let report = Report {
+                    found: drifts.len(),
+                    repaired: drifts.iter().filter(|drift| drift.repaired).count(),
+                    failed: drifts.iter().filter(|drift| drift.error.is_some()).count(),
+                    drifts,
+                };
+                if let Err(e) = send_to_kafka(&config.kafka, "notif", &report, None).await {
+                    error!("failed to send the reconciliation report: {e}");
+                }
+            }
+            Err(e) => {
+                error!("reconciliation failed: {e}");
+                if let Err(e) = send_error(&config.kafka, "error", &*e, "reconcile").await {
+                    error!("{e}");
+                }
+            }
+        }
+    }
+    info!("reconciliation loop stopped");
+}
+
+#[cfg(test)]
+mod test_reconcile {
+    use mockito::{Mock, Server as MockServer, ServerGuard};
+
+    use super::*;
+    use crate::utils::test::configure;
+
+    const GROUP: &str = "9f425a8d-7efc-4768-8f23-7647a74fdf13";
+
+    fn identity(id: &str, schema: &str, metadata: Value) -> Value {
+        json!({
+            "id": id,
+            "schema_id": schema,
+            "schema_url": "",
+            "traits": {"name": id},
+            "metadata_public": metadata
+        })
+    }
+
+    fn identities() -> Value {
+        json!([
+            identity(
+                GROUP,
+                "group",
+                json!({
+                    "project": [122],
+                    "user": {"synced": ["admin"], "outdated": ["admin"], "missing": ["viewer"]}
+                })
+            ),
+            identity(
+                "synced",
+                "default",
+                json!({
+                    "group": {GROUP: {"name": GROUP, "project": ["122"], "role": ["admin"]}}
+                })
+            ),
+            identity(
+                "outdated",
+                "default",
+                json!({
+                    "group": {GROUP: {"name": GROUP, "project": [], "role": ["admin"]}}
+                })
+            ),
+            identity("missing", "default", json!({})),
+            identity("other", "organisation", json!({})),
+            identity(
+                "organisation",
+                "organisation",
+                json!({"project": {}, "group": {GROUP: "default"}})
+            ),
+            identity(
+                "stale",
+                "default",
+                json!({
+                    "group": {GROUP: {"name": GROUP, "project": ["122"], "role": ["admin"]}}
+                })
+            ),
+        ])
+    }
+
+    /// Mock the reads of the identities made by the repairs.
+    async fn mock_reads(server: &mut ServerGuard, identities: &Value) -> Vec<Mock> {
+        let mut mocks = Vec::new();
+        for identity in identities.as_array().unwrap() {
+            let path = format!("/admin/identities/{}", identity["id"].as_str().unwrap());
+            let mock = server
+                .mock("GET", path.as_str())
+                .with_status(200)
+                .with_header("content-type", "application/json")
+                .with_body(identity.to_string())
+                .create_async()
+                .await;
+            mocks.push(mock);
+        }
+        mocks
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_controller() {
+        let mut kratos_server = MockServer::new_async().await;
+        let config = Arc::new(configure(Some(&kratos_server), None, None).await);
+        let mock_kratos = kratos_server
+            .mock("GET", "/admin/identities?page=1&per_page=250")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(identities().to_string())
+            .expect(2)
+            .create_async()
+            .await;
+        let mut drifts = reconcile_controller(config.clone(), Some(GROUP), false)
+            .await
+            .unwrap();
+        drifts.sort_by(|a, b| a.user.cmp(&b.user));
+        let found = drifts
+            .iter()
+            .map(|drift| (drift.user.as_str(), drift.kind))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            found,
+            vec![
+                ("missing", DriftKind::Missing),
+                ("outdated", DriftKind::Outdated),
+                ("stale", DriftKind::Stale)
+            ]
+        );
+        assert!(drifts.iter().all(|drift| !drift.repaired));
+        mock_reads(&mut kratos_server, &identities()).await;
+        let drifts = reconcile_controller(config, None, true).await.unwrap();
+        assert_eq!(drifts.len(), 3);
+        assert!(drifts.iter().all(|drift| drift.user != "organisation"));
+        assert!(drifts.iter().all(|drift| drift.repaired));
+        mock_kratos.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_repair_fresh_read() {
+        let mut kratos_server = MockServer::new_async().await;
+        let config = Arc::new(configure(Some(&kratos_server), None, None).await);
+        kratos_server
+            .mock("GET", "/admin/identities?page=1&per_page=250")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(identities().to_string())
+            .create_async()
+            .await;
+        // the copy of outdated was brought back in sync since the snapshot
+        let mut fresh = identities();
+        fresh[2]["metadata_public"]["group"][GROUP]["project"] = json!(["122"]);
+        let reads = mock_reads(&mut kratos_server, &fresh).await;
+        let mut drifts = reconcile_controller(config, Some(GROUP), true)
+            .await
+            .unwrap();
+        drifts.sort_by(|a, b| a.user.cmp(&b.user));
+        let repaired = drifts
+            .iter()
+            .map(|drift| (drift.user.as_str(), drift.repaired))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            repaired,
+            vec![("missing", true), ("outdated", false), ("stale", true)]
+        );
+        assert!(drifts.iter().all(|drift| drift.error.is_none()));
+        reads[0].assert_async().await;
+        reads[2].assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_ensure_copies_admin() {
+        let config = configure(None, None, None).await;
+        let metadatas: HashMap<String, Metadata> = HashMap::from([(
+            "admin".to_owned(),
+            serde_json::from_value(json!({
+                "group": {GROUP: {"name": GROUP, "project": [], "role": ["admin"]}}
+            }))
+            .unwrap(),
+        )]);
+        let admins = HashMap::from([("admin".to_owned(), Roles(vec!["admin".to_owned()]))]);
+        let viewers = HashMap::from([("admin".to_owned(), Roles(vec!["viewer".to_owned()]))]);
+        fn expected(users: &HashMap<String, Roles>) -> Expected<'_> {
+            Expected {
+                id: GROUP,
+                name: json!(GROUP),
+                projects: Vec::new(),
+                users,
+            }
+        }
+        ensure_copies_admin(&config, &expected(&admins), &metadatas).unwrap();
+        // the copies of a group which lost its admins are not repaired
+        assert!(ensure_copies_admin(&config, &expected(&viewers), &metadatas).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reconcile_batch() {
+        let mut kratos_server = MockServer::new_async().await;
+        let mut config = configure(Some(&kratos_server), None, None).await;
+        config.reconcile.budget = 1;
+        let config = Arc::new(config);
+        let mock_kratos = kratos_server
+            .mock("GET", "/admin/identities?page=1&per_page=250")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(identities().to_string())
+            .expect(3)
+            .create_async()
+            .await;
+        let (drifts, next) = reconcile_batch(config.clone(), 0).await.unwrap();
+        assert_eq!((drifts.len(), next), (3, 1));
+        let (drifts, next) = reconcile_batch(config.clone(), next).await.unwrap();
+        assert_eq!((drifts.len(), next), (0, 0));
+        let (_, next) = reconcile_batch(config, 5).await.unwrap();
+        assert_eq!(next, 1);
+        mock_kratos.assert_async().await;
+    }
+}
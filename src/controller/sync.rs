@@ -2,52 +2,119 @@ use std::{collections::HashMap, sync::Arc};
 
 use anyhow::{anyhow, bail, Result};
 use ory_kratos_client::models::Identity;
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use tonic::Request;
-use tracing::{error, log::info};
+use tonic::{Code, Request, Status};
+use tracing::log::{error, info, warn};
 
 use crate::{
     config::SiriusConfig,
+    controller::list::detail_controller,
+    error::RouterError,
+    metadata::{parse_metadata, Roles},
     permission::{Input, Mode},
-    utils::{error::send_error, kafka::send_to_kafka},
+    utils::{authz::ensure_removal_allowed, index::schedule_refresh, kratos::read_identity},
 };
+/// Number of times a patch rejected by a concurrent update of its group is recomputed and sent
+/// again.
+const CONFLICT_RETRIES: usize = 3;
+
 /// Enum representing the diferent sync mode.
-#[derive(Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum SyncMode {
     User(Vec<(String, Value)>),
     Project(Vec<String>),
+    RemoveUser(Vec<String>),
+    RemoveProject(Vec<String>),
 }
 
-/// Extract all the id needing synchronization.
-fn extract_sync_id(
-    identity: &mut Identity,
-    sync_type: &str,
-    config: &Arc<SiriusConfig>,
-) -> Result<HashMap<String, Vec<Value>>> {
-    let meta = match &config.opa.mode as &str {
-        "admin" => &mut identity.metadata_admin,
-        "public" => &mut identity.metadata_public,
-        "trait" => &mut identity.traits,
-        _ => bail!("Invalid mode! please put a valid mode (admin, public or trait) in the config"),
-    };
+/// Structure representing the outcome of a sync, the errors are indexed by user id.
+/// The users left unpatched by concurrent updates are also listed in the conflicts.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct SyncReport {
+    pub patched: usize,
+    pub errors: HashMap<String, String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub conflicts: Vec<String>,
+}
 
-    let Some(metadata) = meta else {
-        error!("No metadata in this group!");
-        bail!("No metadata in this group!")
-    };
-    let mut ret = HashMap::new();
-    if let Some(val) = metadata.get(sync_type) {
-        if let Some(val) = val.as_object() {
-            for (k, v) in val {
-                let v = match v.as_array() {
-                    Some(value) => value.to_owned(),
-                    None => Vec::new(),
-                };
-                ret.insert(k.clone(), v);
+impl SyncReport {
+    /// Record the result of the patch of an user.
+    fn record(&mut self, user: &str, result: Result<()>) {
+        match result {
+            Ok(()) => self.patched += 1,
+            Err(e) => {
+                error!("failed to patch user {user}: {e}");
+                if matches!(e.downcast_ref(), Some(RouterError::Conflict(_))) {
+                    self.conflicts.push(user.to_owned());
+                }
+                self.errors.insert(user.to_owned(), e.to_string());
             }
         }
-    };
-    Ok(ret)
+    }
+
+    /// Add the counts and errors of another report.
+    pub fn merge(&mut self, other: SyncReport) {
+        self.patched += other.patched;
+        self.errors.extend(other.errors);
+        self.conflicts.extend(other.conflicts);
+    }
+
+    /// Fail if an user could not be patched, as a conflict if only concurrent updates
+    /// prevented it.
+    pub fn into_result(mut self) -> Result<()> {
+        if !self.conflicts.is_empty() && self.conflicts.len() == self.errors.len() {
+            self.conflicts.sort();
+            return Err(RouterError::Conflict(self.conflicts.join(", ")).into());
+        }
+        if !self.errors.is_empty() {
+            let mut users: Vec<_> = self.errors.into_keys().collect();
+            users.sort();
+            bail!("failed to patch users: {}", users.join(", "));
+        }
+        Ok(())
+    }
+}
+
+/// Structure representing a permission written in the metadata of an identity by a sync,
+/// a permission without value is removed.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Patch {
+    pub id: String,
+    pub perm_type: String,
+    pub resource: String,
+    pub value: Option<Value>,
+}
+
+/// Extract the id of the default group of an organisation.
+fn extract_default_group(config: &Arc<SiriusConfig>, identity: &Identity) -> Result<String> {
+    info!("recuparating default group");
+    let metadata = parse_metadata(config, identity)?;
+    metadata
+        .group
+        .into_iter()
+        .find(|(_, entry)| entry.name == "default")
+        .map(|(id, _)| id)
+        .ok_or_else(|| anyhow!("no default group in organisation {}!", identity.id))
+}
+
+/// Compute the permissions written in the default group of an organisation for the users,
+/// the users without role are removed from it.
+pub fn groups_plan(
+    config: &Arc<SiriusConfig>,
+    identity: &Identity,
+    users: &[(String, Option<Value>)],
+) -> Result<Vec<Patch>> {
+    let default_group_id = extract_default_group(config, identity)?;
+    Ok(users
+        .iter()
+        .map(|(user, role)| Patch {
+            id: default_group_id.clone(),
+            perm_type: "user".to_owned(),
+            resource: user.clone(),
+            value: role.clone(),
+        })
+        .collect())
 }
 
 ///synchronize th groups in the users identities
@@ -56,78 +123,84 @@ pub async fn sync_groups(
     identity: &Identity,
     users: &[(String, Value)],
 ) -> Result<()> {
-    info!("recuparating groups from identity");
-    let meta = match &config.opa.mode as &str {
-        "admin" => &identity.metadata_admin,
-        "public" => &identity.metadata_public,
-        "trait" => &identity.traits,
-        _ => bail!("Invalid mode! please put a valid mode (admin, public or trait) in the config"),
-    };
-    let groups = match meta {
-        Some(ref meta) => match meta.get("group") {
-            Some(grps) => grps.as_object().ok_or_else(|| anyhow!("not an object!"))?,
-            None => {
-                bail!("no groups in metadata!")
-            }
-        },
-        None => {
-            bail!("this organisation as no metadata!")
-        }
-    };
-    let mut default_group_id = String::new();
-    info!("recuparating default group");
-    for (id, data) in groups {
-        println!("data: {data}");
-        let name = data.as_str().ok_or_else(|| anyhow!("name not a string!"))?;
-        if name == "default" {
-            id.clone_into(&mut default_group_id);
-        }
-    }
+    let users: Vec<_> = users
+        .iter()
+        .map(|(user, role)| (user.clone(), Some(role.clone())))
+        .collect();
+    let patches = groups_plan(&config, identity, &users)?;
     info!("sending payload to iam!");
-    for (user, role) in users {
-        info!("patching user: {user}.");
-        send_to_iam(&config, &default_group_id, user, role, "user").await?;
+    for patch in &patches {
+        info!("patching user: {}.", patch.resource);
+        let _guard = config.locks.lock(&patch.id).await?;
+        send_patch(&config, patch, None).await?;
     }
     Ok(())
 }
 
-/// synchronize groups identities
-pub async fn sync_user(
+/// Remove the users from the default group of an organisation, which must keep an admin.
+pub async fn unsync_groups(
     config: Arc<SiriusConfig>,
-    identity: Identity,
-    correlation_id: String,
-    mode: SyncMode,
-) {
-    match sync(&config, identity, mode).await {
-        Ok(()) => {
-            if let Err(e) = send_to_kafka(&config.kafka, "notif", "ok".to_string(), None).await {
-                if let Err(e) = send_error(&config.kafka, "error", &*e, &correlation_id).await {
-                    error!("{e}");
-                    return;
-                }
-            }
-            info!("data synced successfully!");
-        }
-        Err(e) => {
-            error!("an error has occurred when syncing data: {e}");
-            let res = send_error(&config.kafka, "error", &*e, &correlation_id).await;
-            if let Err(e) = send_to_kafka(&config.kafka, "notif", "ko", None).await {
-                error!("{e}");
-            }
-            if let Err(e) = res {
-                error!("{e}");
-            }
-        }
+    identity: &Identity,
+    users: &[String],
+) -> Result<()> {
+    let default_group = extract_default_group(&config, identity)?;
+    let _guard = config.locks.lock(&default_group).await?;
+    let group = read_identity(&config, &default_group).await?;
+    let members = detail_controller(&group, &config).await?.user;
+    ensure_removal_allowed(&config, &default_group, &members, users)?;
+    let users: Vec<_> = users.iter().map(|user| (user.clone(), None)).collect();
+    let patches = groups_plan(&config, identity, &users)?;
+    info!("sending payload to iam!");
+    for patch in &patches {
+        info!("removing user: {}.", patch.resource);
+        send_patch(&config, patch, None).await?;
     }
+    Ok(())
 }
 
 /// Send data to iam to replace data in an identity.
-async fn send_to_iam(
+pub async fn send_to_iam(
     config: &Arc<SiriusConfig>,
     id: &str,
     ressource_id: &str,
     json: &serde_json::Value,
     perm_type: &str,
+) -> Result<()> {
+    let patch = Patch {
+        id: id.to_owned(),
+        perm_type: perm_type.to_owned(),
+        resource: ressource_id.to_owned(),
+        value: Some(json.clone()),
+    };
+    let _guard = config.locks.lock(id).await?;
+    send_patch(config, &patch, None).await
+}
+
+/// Send data to iam to remove a ressource from an identity.
+pub async fn remove_from_iam(
+    config: &Arc<SiriusConfig>,
+    id: &str,
+    ressource_id: &str,
+    perm_type: &str,
+) -> Result<()> {
+    let patch = Patch {
+        id: id.to_owned(),
+        perm_type: perm_type.to_owned(),
+        resource: ressource_id.to_owned(),
+        value: None,
+    };
+    let _guard = config.locks.lock(id).await?;
+    send_patch(config, &patch, None).await
+}
+
+/// Send a patch to iam, replacing or removing the permission, the caller holds the lock of
+/// the identity.
+/// With a source iam only writes the identity if the source was not modified since it was
+/// read.
+pub async fn send_patch(
+    config: &Arc<SiriusConfig>,
+    patch: &Patch,
+    source: Option<&Identity>,
 ) -> Result<()> {
     let mut iam_client = config
         .iam
@@ -135,10 +208,13 @@ async fn send_to_iam(
         .clone()
         .ok_or_else(|| anyhow!("Iam client not initialized!"))?;
     let mut input = Input {
-        id: id.to_owned(),
-        perm_type: perm_type.to_owned(),
-        resource: ressource_id.to_owned(),
-        value: serde_json::to_string(&json).unwrap(),
+        id: patch.id.clone(),
+        perm_type: patch.perm_type.clone(),
+        resource: patch.resource.clone(),
+        source: source.map(|source| source.id.clone()).unwrap_or_default(),
+        expected_version: source
+            .and_then(|source| source.updated_at.clone())
+            .unwrap_or_default(),
         ..Default::default()
     };
     let mode = match &config.opa.mode as &str {
@@ -148,113 +224,273 @@ async fn send_to_iam(
         _ => bail!("Invalid mode! please put a valid mode (admin, public or trait) in the config"),
     };
     input.set_mode(mode);
-    let request = Request::new(input);
-    iam_client.replace_permission(request).await?;
+    let sent = match &patch.value {
+        Some(value) => {
+            input.value = serde_json::to_string(value)?;
+            iam_client.replace_permission(Request::new(input)).await
+        }
+        None => iam_client.remove_permission(Request::new(input)).await,
+    };
+    config.kratos_cache.invalidate(&patch.id);
+    sent?;
+    schedule_refresh(config, &patch.id);
     Ok(())
 }
 
-/// Extract project ids already present in the identity.
-fn extract_old_project(meta: &mut Value) -> Result<Vec<String>> {
-    let old_projects = match meta.get_mut("project") {
-        Some(proj) => proj,
-        None => &mut Value::Null,
-    };
+/// Check if iam rejected a write because its source changed since it was read.
+fn is_conflict(error: &anyhow::Error) -> bool {
+    error
+        .downcast_ref::<Status>()
+        .is_some_and(|status| matches!(status.code(), Code::Aborted | Code::FailedPrecondition))
+}
 
-    let projects = match old_projects {
-        Value::Object(projects) => projects
-            .keys()
-            .map(|e| e.as_str().to_owned())
-            .collect::<Vec<String>>(),
-        Value::Array(projects) => {
-            let mut ret = Vec::new();
-            for project in projects {
-                ret.push(
-                    project
-                        .as_u64()
-                        .ok_or_else(|| anyhow!("not a number"))?
-                        .to_string(),
-                );
+/// Index the patches of a sync by user.
+fn plan_by_user(
+    config: &Arc<SiriusConfig>,
+    group: &Identity,
+    mode: &SyncMode,
+) -> Result<HashMap<String, Patch>> {
+    Ok(sync_plan(config, group, mode.clone())?
+        .into_iter()
+        .map(|patch| (patch.id.clone(), patch))
+        .collect())
+}
+
+/// Check that the group was not modified since it was read, by reading it again.
+/// The writes of sirius hold the lock of the group, this catches the other writers of kratos.
+async fn ensure_unchanged(config: &Arc<SiriusConfig>, group: &Identity) -> Result<()> {
+    let current = read_identity(config, &group.id).await?;
+    if current.updated_at != group.updated_at {
+        Err(Status::aborted(format!(
+            "{} was modified since it was read",
+            group.id
+        )))?;
+    }
+    Ok(())
+}
+
+/// Send the patch of an user, guarded by the version of the group it was computed from.
+/// When iam supports it the version is checked by iam with the write, otherwise the group is
+/// read again before the write and compared with it.
+/// On a conflict the group is read again and the plan recomputed from it for this user and the
+/// next ones, the user is given up after [`CONFLICT_RETRIES`] retries.
+async fn sync_patch(
+    config: &Arc<SiriusConfig>,
+    group: &mut Identity,
+    mode: &SyncMode,
+    plan: &mut HashMap<String, Patch>,
+    user: &str,
+) -> Result<()> {
+    let mut retries = 0;
+    loop {
+        let Some(patch) = plan.get(user) else {
+            info!("{user} left the group {}, nothing to patch.", group.id);
+            return Ok(());
+        };
+        let source = config.iam.versioned.then_some(&*group);
+        // the lock of the group is already held by the sync
+        let guard = match user == group.id {
+            true => None,
+            false => Some(config.locks.lock(user).await?),
+        };
+        let sent = match source {
+            Some(_) => send_patch(config, patch, source).await,
+            None => match ensure_unchanged(config, group).await {
+                Ok(()) => send_patch(config, patch, None).await,
+                Err(e) => Err(e),
+            },
+        };
+        drop(guard);
+        match sent {
+            Err(e) if is_conflict(&e) => warn!("concurrent update of {}: {e}", group.id),
+            Ok(()) if user == group.id => {
+                // the group patched itself, its own write is not a conflict for the next users
+                group.updated_at = read_identity(config, &group.id).await?.updated_at;
+                return Ok(());
             }
-            ret
+            result => return result,
         }
-        Value::Null => Vec::new(),
-        _ => {
-            bail!("Projects in this metadata are badly formated it should be array, object or null!\nFound: {}", old_projects.to_string());
+        retries += 1;
+        if retries > CONFLICT_RETRIES {
+            return Err(RouterError::Conflict(user.to_owned()).into());
         }
-    };
-    Ok(projects)
+        *group = read_identity(config, &group.id).await?;
+        *plan = plan_by_user(config, group, mode)?;
+    }
+}
+
+/// Build the patches sending the project list of a group to all its users.
+fn projects_plan(
+    id: &str,
+    users: &HashMap<String, Roles>,
+    name: &Value,
+    projects: &[String],
+) -> Vec<Patch> {
+    users
+        .iter()
+        .map(|(user, role)| {
+            let json = json!({
+                "name": name,
+                "project": projects,
+                "role": role
+            });
+            info!("new project list for {user}: {json}");
+            Patch {
+                id: user.clone(),
+                perm_type: "group".to_owned(),
+                resource: id.to_owned(),
+                value: Some(json),
+            }
+        })
+        .collect()
 }
 
 /// Sync user metadata, group metadata and organisation metadata.
 /// The mode dermine the type of metadata to sync.
-pub async fn sync(
+pub async fn sync(config: &Arc<SiriusConfig>, id: &str, mode: SyncMode) -> Result<()> {
+    sync_with_report(config, id, mode).await?.into_result()
+}
+
+/// Compute the permissions written in the users metadata by a sync, without sending them.
+pub fn sync_plan(
     config: &Arc<SiriusConfig>,
-    mut identity: Identity,
+    identity: &Identity,
     mode: SyncMode,
-) -> Result<()> {
+) -> Result<Vec<Patch>> {
     let id = identity.id.clone();
-    let meta = match &config.opa.mode as &str {
-        "admin" => &mut identity.metadata_admin,
-        "public" => &mut identity.metadata_public,
-        "trait" => &mut identity.traits,
-        _ => bail!("Invalid mode! please put a valid mode (admin, public or trait) in the config"),
-    };
-    let Some(meta) = meta else {
-        bail!("this group as no metadata!");
-    };
-    let mut projects = extract_old_project(meta)?;
+    let metadata = parse_metadata(config, identity)?;
+    let mut projects = metadata.project.ids();
     let name = match identity.traits {
-        Some(ref mut traits) => traits
-            .get_mut("name")
+        Some(ref traits) => traits
+            .get("name")
             .ok_or_else(|| anyhow!("this group as no name!"))?
-            .take(),
+            .clone(),
         None => bail!("this group as no trait!"),
     };
     info!("old project: {projects:?}");
     info!("sync mode: {mode:?}");
-    match mode {
+    let patches = match mode {
         SyncMode::Project(data) => {
             for new_project in data {
                 if !projects.contains(&new_project) {
                     projects.push(new_project);
                 }
             }
-            let users = extract_sync_id(&mut identity, "user", config)?;
-            for (user, role) in users {
-                let json = json!({
-                    "name": name,
-                    "project": projects,
-                    "role": role
-                });
-                info!("new project list: {json}");
-                info!("patching user: {user}.");
-                send_to_iam(config, &user, &id, &json, "group").await?;
-            }
+            projects_plan(&id, &metadata.user, &name, &projects)
+        }
+        SyncMode::RemoveProject(data) => {
+            projects.retain(|project| !data.contains(project));
+            projects_plan(&id, &metadata.user, &name, &projects)
         }
-        SyncMode::User(data) => {
-            for (user, role) in data {
-                let json = json!({
+        SyncMode::User(data) => data
+            .into_iter()
+            .map(|(user, role)| Patch {
+                id: user,
+                perm_type: "group".to_owned(),
+                resource: id.clone(),
+                value: Some(json!({
                     "name": name,
                     "project": projects,
                     "role": role
-                });
-                send_to_iam(config, &user, &id, &json, "group").await?;
-            }
-        }
+                })),
+            })
+            .collect(),
+        SyncMode::RemoveUser(data) => data
+            .into_iter()
+            .map(|user| Patch {
+                id: user,
+                perm_type: "group".to_owned(),
+                resource: id.clone(),
+                value: None,
+            })
+            .collect(),
     };
-    Ok(())
+    Ok(patches)
+}
+
+/// Sync the metadata like [`sync`] but keep patching the users when one of them fails,
+/// the errors are collected in the returned report.
+pub async fn sync_with_report(
+    config: &Arc<SiriusConfig>,
+    id: &str,
+    mode: SyncMode,
+) -> Result<SyncReport> {
+    sync_group(config, id, mode, true).await
+}
+
+/// Remove a group or organisation about to be deleted from all its users, it does not have to
+/// keep an admin.
+pub async fn unsync_deleted(
+    config: &Arc<SiriusConfig>,
+    id: &str,
+    users: Vec<String>,
+) -> Result<()> {
+    sync_group(config, id, SyncMode::RemoveUser(users), false)
+        .await?
+        .into_result()
+}
+
+/// Sync a group to its users, collecting the errors in the returned report.
+/// The group is read and its users patched under its lock, so that a concurrent update of the
+/// group is not overwritten by a plan computed from an older read. When guarded, the users
+/// still members of the group can only lose their copy of it if an admin is left.
+async fn sync_group(
+    config: &Arc<SiriusConfig>,
+    id: &str,
+    mode: SyncMode,
+    guarded: bool,
+) -> Result<SyncReport> {
+    let mut report = SyncReport::default();
+    let _guard = config.locks.lock(id).await?;
+    let mut group = read_identity(config, id).await?;
+    if let (true, SyncMode::RemoveUser(users)) = (guarded, &mode) {
+        let members = detail_controller(&group, config).await?.user;
+        ensure_removal_allowed(config, id, &members, users)?;
+    }
+    let mut plan = plan_by_user(config, &group, &mode)?;
+    let mut users: Vec<String> = plan.keys().cloned().collect();
+    users.sort();
+    for user in users {
+        info!("patching user: {user}.");
+        let result = sync_patch(config, &mut group, &mode, &mut plan, &user).await;
+        report.record(&user, result);
+    }
+    Ok(report)
 }
 
 #[cfg(test)]
 mod test_sync {
     use serde_json::Value;
-    use std::sync::Arc;
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
     use uuid::Uuid;
 
-    use crate::utils::test::{configure, IDENTITY_GROUP, IDENTITY_ORG};
+    use mockito::{Mock, Server as MockServer, ServerGuard};
+
+    use crate::utils::test::{
+        configure, IDENTITY_GROUP, IDENTITY_GROUP_ADMIN, IDENTITY_ORG, IDENTITY_ORG_ADMIN,
+        STALE_VERSION,
+    };
 
     use super::*;
 
+    const GROUP: &str = "af25f904-5319-4011-95a4-343365d64811";
+    const GROUP_PATH: &str = "/admin/identities/af25f904-5319-4011-95a4-343365d64811";
+
+    /// Serve the group read by a sync, the users refreshed after their patch share its id.
+    async fn mock_group(server: &mut ServerGuard, body: &str) -> Mock {
+        server
+            .mock("GET", GROUP_PATH)
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(body)
+            .expect_at_least(1)
+            .create_async()
+            .await
+    }
+
     #[tokio::test]
     async fn test_send_to_iam() {
         let json = Value::Array(vec![Value::String("admin".to_owned())]);
@@ -269,11 +505,193 @@ mod test_sync {
 
     #[tokio::test]
     async fn test_sync_simple() {
-        let identity = serde_json::from_str(IDENTITY_GROUP).unwrap();
+        let mut kratos_server = MockServer::new_async().await;
+        let kratos_mock = mock_group(&mut kratos_server, IDENTITY_GROUP).await;
         let mode = SyncMode::Project(vec!["test".to_owned(), "test".to_owned()]);
-        let config = configure(None, None, None).await;
+        let config = configure(Some(&kratos_server), None, None).await;
         let config = Arc::new(config);
-        sync(&config, identity, mode).await.unwrap();
+        sync(&config, GROUP, mode).await.unwrap();
+        kratos_mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_remove_from_iam() {
+        let user = Uuid::new_v4().to_string();
+        let id = Uuid::new_v4().to_string();
+        let config = Arc::new(configure(None, None, None).await);
+        remove_from_iam(&config, &user, &id, "group").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_sync_remove_project() {
+        let mut kratos_server = MockServer::new_async().await;
+        let kratos_mock = mock_group(&mut kratos_server, IDENTITY_GROUP_ADMIN).await;
+        let mode = SyncMode::RemoveProject(vec!["122".to_owned()]);
+        let config = Arc::new(configure(Some(&kratos_server), None, None).await);
+        sync(&config, GROUP, mode).await.unwrap();
+        kratos_mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_sync_remove_user() {
+        let mut kratos_server = MockServer::new_async().await;
+        let kratos_mock = mock_group(&mut kratos_server, IDENTITY_GROUP_ADMIN).await;
+        let mode = SyncMode::RemoveUser(vec![Uuid::new_v4().to_string()]);
+        let config = Arc::new(configure(Some(&kratos_server), None, None).await);
+        sync(&config, GROUP, mode).await.unwrap();
+        kratos_mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_sync_unknown_group() {
+        let config = Arc::new(configure(None, None, None).await);
+        let mode = SyncMode::RemoveProject(vec!["122".to_owned()]);
+        assert!(sync(&config, GROUP, mode).await.is_err());
+        assert!(config.locks.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_sync_with_report() {
+        let mut kratos_server = MockServer::new_async().await;
+        let _kratos_mock = mock_group(&mut kratos_server, IDENTITY_GROUP_ADMIN).await;
+        let users = vec![("1".to_owned(), Value::Null), ("2".to_owned(), Value::Null)];
+        let mut config = configure(Some(&kratos_server), None, None).await;
+        let mode = SyncMode::User(users);
+        let report = sync_with_report(&Arc::new(config.clone()), GROUP, mode.clone())
+            .await
+            .unwrap();
+        assert_eq!(report.patched, 2);
+        assert!(report.errors.is_empty());
+        config.iam.client = None;
+        let report = sync_with_report(&Arc::new(config), GROUP, mode)
+            .await
+            .unwrap();
+        assert_eq!(report.patched, 0);
+        assert_eq!(report.errors.len(), 2);
+        assert!(report.into_result().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_sync_conflict() {
+        let mut kratos_server = MockServer::new_async().await;
+        let mut config = configure(Some(&kratos_server), None, None).await;
+        let mut identity: Identity = serde_json::from_str(IDENTITY_GROUP_ADMIN).unwrap();
+        identity.updated_at = Some(STALE_VERSION.to_owned());
+        let mode = SyncMode::Project(vec!["test".to_owned()]);
+        config.iam.versioned = true;
+        let kratos_mock = kratos_server
+            .mock("GET", GROUP_PATH)
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(serde_json::to_string(&identity).unwrap())
+            .expect(CONFLICT_RETRIES + 1)
+            .create_async()
+            .await;
+        let report = sync_with_report(&Arc::new(config.clone()), GROUP, mode.clone())
+            .await
+            .unwrap();
+        kratos_mock.assert_async().await;
+        assert_eq!(report.patched, 0);
+        assert_eq!(report.conflicts, ["af25f904-5319-4011-95a4-343365d64811"]);
+        let error = RouterError::from(report.into_result().unwrap_err());
+        assert!(matches!(error, RouterError::Conflict(_)));
+        // without the support of iam the version is not sent
+        config.iam.versioned = false;
+        let report = sync_with_report(&Arc::new(config), GROUP, mode)
+            .await
+            .unwrap();
+        assert_eq!(report.patched, 1);
+    }
+
+    #[tokio::test]
+    async fn test_sync_unversioned_conflict() {
+        let mut kratos_server = MockServer::new_async().await;
+        let config = Arc::new(configure(Some(&kratos_server), None, None).await);
+        let served = AtomicUsize::new(0);
+        // the group is modified outside sirius between the read of the sync and its check
+        let kratos_mock = kratos_server
+            .mock("GET", GROUP_PATH)
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body_from_request(move |_| {
+                let mut identity: Identity = serde_json::from_str(IDENTITY_GROUP_ADMIN).unwrap();
+                if served.fetch_add(1, Ordering::SeqCst) == 0 {
+                    identity.updated_at = Some(STALE_VERSION.to_owned());
+                }
+                serde_json::to_vec(&identity).unwrap()
+            })
+            // the sync read, the failed check, the read again, the check and the read after the
+            // group patched itself
+            .expect_at_least(5)
+            .create_async()
+            .await;
+        let mode = SyncMode::Project(vec!["test".to_owned()]);
+        let report = sync_with_report(&config, GROUP, mode).await.unwrap();
+        kratos_mock.assert_async().await;
+        assert_eq!(report.patched, 1);
+        assert!(report.conflicts.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_sync_plan() {
+        let identity: Identity = serde_json::from_str(IDENTITY_GROUP_ADMIN).unwrap();
+        let config = Arc::new(configure(None, None, None).await);
+        let mode = SyncMode::RemoveProject(vec!["122".to_owned()]);
+        let patches = sync_plan(&config, &identity, mode).unwrap();
+        assert_eq!(patches.len(), 1);
+        assert_eq!(patches[0].id, "af25f904-5319-4011-95a4-343365d64811");
+        assert_eq!(patches[0].resource, identity.id);
+        let value = patches[0].value.as_ref().unwrap();
+        assert_eq!(value["project"], json!(["334", "456"]));
+        let mode = SyncMode::RemoveUser(vec!["1".to_owned()]);
+        let patches = sync_plan(&config, &identity, mode).unwrap();
+        assert_eq!(patches[0].value, None);
+    }
+
+    #[tokio::test]
+    async fn test_unsync_groups_simple() {
+        let mut kratos_server = MockServer::new_async().await;
+        let kratos_mock = kratos_server
+            .mock(
+                "GET",
+                "/admin/identities/7113206d-afc0-41ad-bbca-b1e8113beb82",
+            )
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(IDENTITY_GROUP_ADMIN)
+            .expect_at_least(2)
+            .create_async()
+            .await;
+        let identity: Identity = serde_json::from_str(IDENTITY_ORG_ADMIN).unwrap();
+        let config = Arc::new(configure(Some(&kratos_server), None, None).await);
+        unsync_groups(config.clone(), &identity, &["test".to_owned()])
+            .await
+            .unwrap();
+        // the only admin of the default group can not be removed
+        let error = unsync_groups(config, &identity, &[GROUP.to_owned()])
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            RouterError::from(error),
+            RouterError::LastAdmin(_)
+        ));
+        kratos_mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_sync_remove_admin() {
+        let mut kratos_server = MockServer::new_async().await;
+        let _kratos_mock = mock_group(&mut kratos_server, IDENTITY_GROUP_ADMIN).await;
+        let config = Arc::new(configure(Some(&kratos_server), None, None).await);
+        let mode = SyncMode::RemoveUser(vec![GROUP.to_owned()]);
+        let error = sync(&config, GROUP, mode).await.unwrap_err();
+        assert!(matches!(
+            RouterError::from(error),
+            RouterError::LastAdmin(_)
+        ));
+        unsync_deleted(&config, GROUP, vec![GROUP.to_owned()])
+            .await
+            .unwrap();
     }
 
     #[tokio::test]
@@ -1,22 +1,154 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 
-use anyhow::{anyhow, bail, Ok, Result};
+use anyhow::{anyhow, bail, Result};
+use axum::http::Method;
+use axum::http::StatusCode;
 use ory_kratos_client::{
     apis::{configuration::Configuration, identity_api::get_identity},
     models::Identity,
 };
-use tokio::task::JoinSet;
+use serde::Serialize;
+use serde_json::Value;
+use tokio::task::{JoinError, JoinSet};
 use tonic::Request;
-use tracing::{debug, info};
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
 
+#[cfg(not(feature = "opa"))]
+use crate::utils::authz::authorize;
+use crate::utils::authz::{ensure_admin_left, ensure_grantable, ensure_writable};
 #[cfg(feature = "opa")]
 use crate::utils::opa::validate_roles;
 use crate::{
     config::SiriusConfig,
+    controller::diff::Diff,
+    error::RouterError,
+    metadata::raw_metadata,
     permission::{Input, Mode},
     router::{Data, IDType},
+    utils::{cache::cached_identity, index::schedule_refresh, kratos::read_identity},
 };
 
+/// Enum representing the operation to apply on the identity permissions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    Add,
+    Remove,
+    Replace,
+}
+
+impl Operation {
+    /// Return the http method matching the operation.
+    pub fn method(&self) -> &'static str {
+        match self {
+            Operation::Add => "post",
+            Operation::Remove => "delete",
+            Operation::Replace => "put",
+        }
+    }
+}
+
+impl TryFrom<&Method> for Operation {
+    type Error = anyhow::Error;
+
+    fn try_from(method: &Method) -> Result<Self> {
+        match *method {
+            Method::POST => Ok(Operation::Add),
+            Method::DELETE => Ok(Operation::Remove),
+            Method::PUT => Ok(Operation::Replace),
+            _ => bail!("unsupported method: {method}"),
+        }
+    }
+}
+
+/// Enum representing the outcome of an item of an update.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ItemStatus {
+    Planned,
+    Applied,
+    Denied,
+    Failed,
+    Reverted,
+    Skipped,
+}
+
+/// Structure representing the result of an item of an update: the identity it resolved to,
+/// the authorization decision, the outcome of the iam call and its http status code.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ItemResult {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub ressource_type: String,
+    pub ressource_id: String,
+    pub identity: Option<String>,
+    pub allowed: Option<bool>,
+    pub status: ItemStatus,
+    pub code: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl ItemResult {
+    fn new(data: &Data) -> Self {
+        ItemResult {
+            id: data.id.to_string(),
+            ressource_type: data.ressource_type.clone(),
+            ressource_id: data.ressource_id.clone(),
+            identity: None,
+            allowed: None,
+            status: ItemStatus::Skipped,
+            code: StatusCode::FAILED_DEPENDENCY.as_u16(),
+            error: None,
+        }
+    }
+
+    /// Record the error of the item, the authorization errors deny it.
+    fn fail(&mut self, error: &anyhow::Error) {
+        let code = match error.downcast_ref::<RouterError>() {
+            Some(error) => error.status(),
+            None if error.downcast_ref::<tonic::Status>().is_some() => StatusCode::BAD_GATEWAY,
+            None => StatusCode::INTERNAL_SERVER_ERROR,
+        };
+        self.status = match code {
+            StatusCode::FORBIDDEN => ItemStatus::Denied,
+            _ => ItemStatus::Failed,
+        };
+        self.code = code.as_u16();
+        self.error = Some(error.to_string());
+    }
+}
+
+/// Structure representing the results of the items of an update with the error which
+/// stopped it, if any. The diff of the metadata is only computed in dry run.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct MultiStatus {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub items: Vec<ItemResult>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub diff: Vec<Diff>,
+}
+
+/// Structure representing a permission sent to iam with its value before the update.
+struct Applied {
+    item: usize,
+    identity: Arc<Identity>,
+    data: Data,
+    previous: Previous,
+}
+
+/// Enum representing the state of a permission before an update.
+#[derive(Debug, PartialEq)]
+enum Previous {
+    /// The resource was not set.
+    Unset,
+    /// The resource held the value in a map field.
+    Value(Value),
+    /// The resource was listed in an array field, which holds no value per resource.
+    Listed,
+}
+
 /// Get an identities from kratos by mail.
 async fn get_identity_by_mail(client: &Configuration, id: &str) -> Result<Identity> {
     let mut addr = format!("{}/admin/identities", client.base_path);
@@ -32,20 +164,38 @@ async fn get_identity_by_mail(client: &Configuration, id: &str) -> Result<Identi
     Ok(identity)
 }
 
-/// Get an identity from kratos.
-async fn get_kratos_identity(config: &SiriusConfig, id: &IDType) -> Result<Identity> {
+/// Get an identity from kratos, or from the cache if it was read recently.
+pub async fn get_kratos_identity(config: &SiriusConfig, id: &IDType) -> Result<Identity> {
     let Some(client) = &config.kratos.client else {
         bail!("kratos client not initialized")
     };
-    let identity = match id {
-        IDType::Email(id) => get_identity_by_mail(client, id.as_str()).await?,
-        IDType::ID(ref id) => get_identity(client, &id.to_string(), None).await?,
+    let fetch = async {
+        let identity = match id {
+            IDType::Email(id) => get_identity_by_mail(client, id.as_str()).await?,
+            IDType::ID(ref id) => get_identity(client, &id.to_string(), None).await?,
+        };
+        Ok(identity)
     };
+    cached_identity(config, &id.to_string(), fetch).await
+}
+
+/// Get a group or an organisation from kratos, an identity of another kind is not found.
+pub async fn get_kind_identity(config: &SiriusConfig, id: Uuid, kind: &str) -> Result<Identity> {
+    let identity = get_kratos_identity(config, &IDType::ID(id)).await?;
+    if identity.schema_id != config.schemas.of(kind) {
+        error!("{id} is not a {kind}");
+        Err(RouterError::Status(StatusCode::NOT_FOUND))?;
+    }
     Ok(identity)
 }
 
-/// Send data to iam to add permition to an identity.
-async fn send_to_iam(identity: Arc<Identity>, config: Arc<SiriusConfig>, data: Data) -> Result<()> {
+/// Send data to iam to add, remove or replace permition of an identity.
+async fn send_to_iam(
+    identity: Arc<Identity>,
+    config: Arc<SiriusConfig>,
+    data: Data,
+    operation: Operation,
+) -> Result<()> {
     let mut client = config
         .iam
         .client
@@ -69,86 +219,392 @@ async fn send_to_iam(identity: Arc<Identity>, config: Arc<SiriusConfig>, data: D
     };
     input.set_mode(mode);
     let request = Request::new(input);
-    client.add_permission(request).await?;
+    let sent = match operation {
+        Operation::Add => client.add_permission(request).await,
+        Operation::Remove => client.remove_permission(request).await,
+        Operation::Replace => client.replace_permission(request).await,
+    };
+    config.kratos_cache.invalidate(&identity.id);
+    sent?;
+    schedule_refresh(&config, &identity.id);
+    Ok(())
+}
+
+/// Get the state of the permission in the metadata of the identity.
+fn previous_value(config: &SiriusConfig, identity: &Identity, data: &Data) -> Previous {
+    let Ok(Some(metadata)) = raw_metadata(config, identity) else {
+        return Previous::Unset;
+    };
+    match metadata.get(&data.ressource_type) {
+        Some(Value::Object(entries)) => match entries.get(&data.ressource_id) {
+            Some(value) => Previous::Value(value.clone()),
+            None => Previous::Unset,
+        },
+        Some(Value::Array(ids)) => {
+            let listed = ids.iter().any(|id| match id {
+                Value::String(id) => *id == data.ressource_id,
+                id => data.ressource_id.parse::<i64>().ok() == id.as_i64(),
+            });
+            match listed {
+                true => Previous::Listed,
+                false => Previous::Unset,
+            }
+        }
+        _ => Previous::Unset,
+    }
+}
+
+/// Read the identity from kratos under its lock, then send the permission to iam.
+/// The state of the permission before the write is returned to revert it.
+async fn apply_to_iam(
+    identity: Arc<Identity>,
+    config: Arc<SiriusConfig>,
+    data: Data,
+    operation: Operation,
+) -> Result<Previous> {
+    let current = read_identity(&config, &identity.id).await?;
+    let previous = previous_value(&config, &current, &data);
+    send_to_iam(identity, config, data, operation).await?;
+    Ok(previous)
+}
+
+/// Revert the permissions applied by an update, restoring their previous value
+/// or removing them if they did not exist. A resource listed in an array field is
+/// listed back, without value, by a resource operation.
+async fn rollback(
+    config: &Arc<SiriusConfig>,
+    applied: &[Applied],
+    mut succeeded: Vec<usize>,
+    operation: Operation,
+    items: &mut [ItemResult],
+) {
+    succeeded.sort_unstable();
+    for index in succeeded.into_iter().rev() {
+        let applied = &applied[index];
+        let item = &mut items[applied.item];
+        let revert = match (&applied.previous, operation) {
+            (Previous::Value(value), _) => Some((value.clone(), Operation::Replace)),
+            (Previous::Listed, Operation::Remove) => Some((Value::Null, Operation::Add)),
+            (Previous::Listed, _) => Some((Value::Null, Operation::Replace)),
+            (Previous::Unset, Operation::Add | Operation::Replace) => {
+                Some((applied.data.value.clone(), Operation::Remove))
+            }
+            (Previous::Unset, Operation::Remove) => None,
+        };
+        if let Some((value, operation)) = revert {
+            let data = Data {
+                value,
+                ..applied.data.clone()
+            };
+            let sent = match config.locks.lock(&applied.identity.id).await {
+                Ok(_guard) => {
+                    send_to_iam(applied.identity.clone(), config.clone(), data, operation).await
+                }
+                Err(e) => Err(e),
+            };
+            if let Err(e) = sent {
+                error!(
+                    "failed to revert {}.{} of {}: {e}",
+                    item.ressource_type, item.ressource_id, applied.identity.id
+                );
+                item.fail(&e.context("failed to revert"));
+                item.status = ItemStatus::Applied;
+                continue;
+            }
+        }
+        item.status = ItemStatus::Reverted;
+        item.code = StatusCode::FAILED_DEPENDENCY.as_u16();
+    }
+}
+
+/// Identities targeted by a payload, indexed by the id of the payload items.
+#[derive(Default)]
+struct Resolved {
+    identities: HashMap<String, Arc<Identity>>,
+    errors: HashMap<String, anyhow::Error>,
+}
+
+/// Get each distinct identity targeted by the payload once, `limits.kratos` of them at a time.
+async fn resolve_identities(config: &Arc<SiriusConfig>, payload: &[Data]) -> Result<Resolved> {
+    let concurrency = config.limits.kratos.max(1);
+    let mut ids = HashMap::new();
+    for data in payload {
+        ids.entry(data.id.to_string()).or_insert(&data.id);
+    }
+    info!("resolving {} identities", ids.len());
+    let mut handles = JoinSet::new();
+    let mut resolved = Resolved::default();
+    let mut record = |(key, identity): (String, Result<Identity>)| match identity {
+        Ok(identity) => {
+            resolved.identities.insert(key, Arc::new(identity));
+        }
+        Err(e) => {
+            resolved.errors.insert(key, e);
+        }
+    };
+    for (key, id) in ids {
+        if handles.len() >= concurrency {
+            if let Some(joined) = handles.join_next().await {
+                record(joined?);
+            }
+        }
+        let (config, id) = (config.clone(), id.clone());
+        handles.spawn(async move { (key, get_kratos_identity(&config, &id).await) });
+    }
+    while let Some(joined) = handles.join_next().await {
+        record(joined?);
+    }
+    Ok(resolved)
+}
+
+/// Record the outcome of an iam call of an update and the state it replaced.
+fn record_sent(
+    joined: Result<(usize, Result<Previous>), JoinError>,
+    applied: &mut [Applied],
+    items: &mut [ItemResult],
+    succeeded: &mut Vec<usize>,
+    failure: &mut Option<anyhow::Error>,
+) {
+    match joined {
+        Ok((sent, Ok(previous))) => {
+            applied[sent].previous = previous;
+            let item = &mut items[applied[sent].item];
+            item.status = ItemStatus::Applied;
+            item.code = StatusCode::OK.as_u16();
+            succeeded.push(sent);
+        }
+        Ok((sent, Err(e))) => {
+            items[applied[sent].item].fail(&e);
+            failure.get_or_insert(e);
+        }
+        Err(e) => {
+            failure.get_or_insert(e.into());
+        }
+    }
+}
+
+/// Check that the item can be written through the endpoint and that the caller is allowed to
+/// apply it and to grant its roles.
+async fn check_item(
+    config: &SiriusConfig,
+    caller: &Identity,
+    endpoint: &str,
+    data: &Data,
+    operation: Operation,
+    _item: &mut ItemResult,
+) -> Result<()> {
+    ensure_writable(endpoint, data, operation)?;
+    #[cfg(not(feature = "opa"))]
+    {
+        let allowed = authorize(config, caller, endpoint, data, operation).await?;
+        _item.allowed = Some(allowed);
+        if !allowed {
+            error!("{} is not allowed to update {}", caller.id, data.id);
+            return Err(
+                anyhow::Error::from(RouterError::Status(StatusCode::FORBIDDEN))
+                    .context(format!("not allowed to update {}", data.id)),
+            );
+        }
+    }
+    if operation != Operation::Remove {
+        ensure_grantable(config, caller, endpoint, data).await?;
+    }
     Ok(())
 }
 
 /// Send a call to iam to update an identity metadata.
+/// The update is all or nothing, if an item fails the ones already applied are reverted.
+/// The identities are resolved once each and at most `limits.iam` calls are in flight.
+/// The result of every item is returned, as the error if the update failed.
+/// In dry run the items are authorized and their identity resolved but nothing is sent.
 pub async fn update_controller(
     config: Arc<SiriusConfig>,
     payload: Vec<Data>,
     _identity: Identity,
     endpoint: &str,
     _correlation_id: &str,
-) -> Result<Identity> {
+    operation: Operation,
+    dry_run: bool,
+) -> Result<(Identity, MultiStatus)> {
     let mut handles = JoinSet::new();
     let mut object_identity: Option<Arc<Identity>> = None;
     let _uri = "api/iam/".to_owned() + endpoint;
-    for data in &payload {
+    let mut items: Vec<ItemResult> = payload.iter().map(ItemResult::new).collect();
+    let mut failure = None;
+    for (data, item) in payload.iter().zip(items.iter_mut()) {
+        if let Err(e) = check_item(&config, &_identity, endpoint, data, operation, item).await {
+            item.fail(&e);
+            failure.get_or_insert(e);
+        }
+    }
+    if failure.is_none() {
+        for (indexes, e) in ensure_admin_left(&config, endpoint, &payload, operation).await {
+            for index in indexes {
+                items[index].fail(&e);
+            }
+            failure.get_or_insert(e);
+        }
+    }
+    if let Some(e) = failure {
+        return Err(RouterError::MultiStatus(MultiStatus {
+            error: Some(e.to_string()),
+            items,
+            diff: Vec::new(),
+        })
+        .into());
+    }
+    let mut resolved = resolve_identities(&config, &payload).await?;
+    let concurrency = config.limits.iam.max(1);
+    let mut applied = Vec::new();
+    let mut succeeded = Vec::new();
+    let mut failure = None;
+    for (index, data) in payload.iter().enumerate() {
         #[cfg(feature = "opa")]
-        if !validate_roles(
+        match validate_roles(
             &config,
             &_identity,
             &data.ressource_id,
             _correlation_id,
             &_uri,
+            operation.method(),
         )
-        .await?
+        .await
         {
-            Err(anyhow!("Invalid role!"))?;
-        }
-        println!("role validated!");
-        if let Some(ref ident) = object_identity {
-            match data.id {
-                IDType::ID(id) if id.to_string() != ident.id => {
-                    object_identity = Some(Arc::new(get_kratos_identity(&config, &data.id).await?));
+            Ok(allowed) => {
+                items[index].allowed = Some(allowed);
+                if !allowed {
+                    let e = anyhow::Error::from(RouterError::Status(StatusCode::FORBIDDEN))
+                        .context("Invalid role!");
+                    items[index].fail(&e);
+                    failure = Some(e);
+                    break;
                 }
-                IDType::Email(ref id) => match &ident.traits {
-                    Some(traits) => match traits.get("email") {
-                        Some(email) if email == id.as_str() => (),
-                        _ => {
-                            object_identity =
-                                Some(Arc::new(get_kratos_identity(&config, &data.id).await?));
-                        }
-                    },
-                    None => {
-                        object_identity =
-                            Some(Arc::new(get_kratos_identity(&config, &data.id).await?));
-                    }
-                },
-                IDType::ID(_) => (),
             }
-        } else {
-            object_identity = Some(Arc::new(get_kratos_identity(&config, &data.id).await?));
+            Err(e) => {
+                items[index].fail(&e);
+                failure = Some(e);
+                break;
+            }
         }
+        println!("role validated!");
+        let key = data.id.to_string();
+        if let Some(e) = resolved.errors.remove(&key) {
+            items[index].fail(&e);
+            failure = Some(e);
+            break;
+        }
+        let Some(ident) = resolved.identities.get(&key).cloned() else {
+            bail!("the identity {key} was not resolved this should not be happening!")
+        };
+        object_identity = Some(ident.clone());
+        items[index].identity = Some(ident.id.clone());
         info!("kratos identity obtained!");
-        match object_identity {
-            Some(ref ident) => {
-                handles.spawn(send_to_iam(ident.clone(), config.clone(), data.to_owned()));
+        if dry_run {
+            items[index].status = ItemStatus::Planned;
+            items[index].code = StatusCode::OK.as_u16();
+            continue;
+        }
+        if handles.len() >= concurrency {
+            if let Some(joined) = handles.join_next().await {
+                record_sent(
+                    joined,
+                    &mut applied,
+                    &mut items,
+                    &mut succeeded,
+                    &mut failure,
+                );
+            }
+            if failure.is_some() {
+                break;
             }
-            None => bail!("the identity is not initialized this should not be happening!"),
         }
+        // the lock is taken in the order of the items and held until the call returns
+        let guard = match config.locks.lock(&ident.id).await {
+            Ok(guard) => guard,
+            Err(e) => {
+                items[index].fail(&e);
+                failure = Some(e);
+                break;
+            }
+        };
+        let sent = applied.len();
+        applied.push(Applied {
+            item: index,
+            identity: ident.clone(),
+            data: data.to_owned(),
+            previous: Previous::Unset,
+        });
+        let request = apply_to_iam(ident, config.clone(), data.to_owned(), operation);
+        handles.spawn(async move {
+            let result = request.await;
+            drop(guard);
+            (sent, result)
+        });
     }
-    while let Some(future) = handles.join_next().await {
-        future??;
+    while let Some(joined) = handles.join_next().await {
+        record_sent(
+            joined,
+            &mut applied,
+            &mut items,
+            &mut succeeded,
+            &mut failure,
+        );
+    }
+    if let Some(e) = failure {
+        warn!(
+            "update failed, reverting {} permissions: {e}",
+            succeeded.len()
+        );
+        rollback(&config, &applied, succeeded, operation, &mut items).await;
+        return Err(RouterError::MultiStatus(MultiStatus {
+            error: Some(e.to_string()),
+            items,
+            diff: Vec::new(),
+        })
+        .into());
+    }
+    match object_identity {
+        Some(ident) => Ok((
+            Arc::unwrap_or_clone(ident),
+            MultiStatus {
+                error: None,
+                items,
+                diff: Vec::new(),
+            },
+        )),
+        None => bail!("the identity is not initialized this should not be happening!"),
     }
-    let ret =
-        Arc::try_unwrap(object_identity.unwrap()).map_err(|_| anyhow!("failed to uwrap arc"))?;
-    Ok(ret)
 }
 
 #[cfg(test)]
 pub mod test_controler {
     use super::*;
-    use mockito::Server as MockServer;
+    use mockito::{Mock, Server as MockServer, ServerGuard};
     use serde_email::Email;
-    use serde_json::Value;
+    use serde_json::{json, Value};
 
     use crate::{
         router::Data,
-        utils::test::{configure, IDENTITY_USER},
+        utils::test::{
+            configure, FAILING_RESOURCE, IDENTITY_GROUP_ADMIN, IDENTITY_USER, IDENTITY_USER_ADMIN,
+        },
     };
 
+    /// Mock the reads of the identity of the fixtures made by the writes, bypassing the cache.
+    async fn mock_read(server: &mut ServerGuard, fixture: &str) -> Mock {
+        server
+            .mock(
+                "GET",
+                "/admin/identities/af25f904-5319-4011-95a4-343365d64811",
+            )
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(fixture)
+            .expect_at_least(1)
+            .create_async()
+            .await
+    }
+
     #[tokio::test]
     async fn test_get_kratos_identity_email() {
         let id = IDType::Email(Email::from_str("lol.lol@lol.io").unwrap());
@@ -180,14 +636,60 @@ pub mod test_controler {
         let identity = Arc::new(serde_json::from_str(IDENTITY_USER).unwrap());
         let config = configure(None, None, None).await;
         let config = Arc::new(config);
-        send_to_iam(identity, config, data).await.unwrap();
+        send_to_iam(identity, config, data, Operation::Add)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_send_to_iam_remove() {
+        let data = Data {
+            id: IDType::Email(Email::from_str("lol.lol@lol.io").unwrap()),
+            ressource_type: "project".to_owned(),
+            ressource_id: "222".to_owned(),
+            value: Value::Array(vec![Value::String("admin".to_owned())]),
+        };
+        let identity = Arc::new(serde_json::from_str(IDENTITY_USER_ADMIN).unwrap());
+        let config = Arc::new(configure(None, None, None).await);
+        send_to_iam(identity, config, data, Operation::Remove)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_send_to_iam_replace() {
+        let data = Data {
+            id: IDType::Email(Email::from_str("lol.lol@lol.io").unwrap()),
+            ressource_type: "project".to_owned(),
+            ressource_id: "222".to_owned(),
+            value: Value::Array(vec![Value::String("viewer".to_owned())]),
+        };
+        let identity = Arc::new(serde_json::from_str(IDENTITY_USER_ADMIN).unwrap());
+        let config = Arc::new(configure(None, None, None).await);
+        send_to_iam(identity, config, data, Operation::Replace)
+            .await
+            .unwrap();
+    }
+
+    #[test]
+    fn test_operation_from_method() {
+        assert_eq!(Operation::try_from(&Method::POST).unwrap(), Operation::Add);
+        assert_eq!(
+            Operation::try_from(&Method::DELETE).unwrap(),
+            Operation::Remove
+        );
+        assert_eq!(
+            Operation::try_from(&Method::PUT).unwrap(),
+            Operation::Replace
+        );
+        assert!(Operation::try_from(&Method::PATCH).is_err());
     }
 
     #[tokio::test]
     async fn test_update_controler_simple() {
         let data = Data {
             id: IDType::Email(Email::from_str("lol.lol@lol.io").unwrap()),
-            ressource_type: "test".to_owned(),
+            ressource_type: "project".to_owned(),
             ressource_id: "222".to_owned(),
             value: Value::Array(vec![Value::String("admin".to_owned())]),
         };
@@ -213,16 +715,21 @@ pub mod test_controler {
         .with_body(r#"true"#)
         .create_async()
         .await; */
-        let identity = serde_json::from_str(IDENTITY_USER).unwrap();
+        // the caller needs a role on the project it updates
+        let read_mock = mock_read(&mut kratos_server, IDENTITY_USER).await;
+        let identity = serde_json::from_str(IDENTITY_USER_ADMIN).unwrap();
         update_controller(
             Arc::new(config),
             vec![data],
             identity,
             "project",
             correlation_id,
+            Operation::Add,
+            false,
         )
         .await
         .unwrap();
+        read_mock.assert_async().await;
         kratos_mock.assert_async().await;
         // opa_mock.assert_async().await;
     }
@@ -231,7 +738,7 @@ pub mod test_controler {
     async fn test_update_controler_multiple() {
         let data = Data {
             id: IDType::Email(Email::from_str("lol.lol@lol.io").unwrap()),
-            ressource_type: "test".to_owned(),
+            ressource_type: "project".to_owned(),
             ressource_id: "222".to_owned(),
             value: Value::String("admin".to_owned()),
         };
@@ -259,17 +766,305 @@ pub mod test_controler {
         .create_async()
         .await; */
 
-        let identity = serde_json::from_str(IDENTITY_USER).unwrap();
+        // the caller needs a role on the project it updates
+        let read_mock = mock_read(&mut kratos_server, IDENTITY_USER).await;
+        let identity = serde_json::from_str(IDENTITY_USER_ADMIN).unwrap();
         update_controller(
             Arc::new(config),
             vec![data.clone(), data],
             identity,
             "project",
             correlation_id,
+            Operation::Add,
+            false,
         )
         .await
         .unwrap();
+        read_mock.assert_async().await;
         kratos_mock.assert_async().await;
         // opa_mock.assert_async().await;
     }
+
+    #[tokio::test]
+    async fn test_update_controler_resolve_once() {
+        let email = IDType::Email(Email::from_str("lol.lol@lol.io").unwrap());
+        let other = IDType::Email(Email::from_str("other@lol.io").unwrap());
+        let data = |id: &IDType, resource: &str| Data {
+            id: id.clone(),
+            ressource_type: "project".to_owned(),
+            ressource_id: resource.to_owned(),
+            value: Value::Array(vec![Value::String("admin".to_owned())]),
+        };
+        let mut kratos_server = MockServer::new_async().await;
+        let mut config = configure(Some(&kratos_server), None, None).await;
+        config.limits.iam = 1;
+        let body = "[".to_owned() + IDENTITY_USER_ADMIN + "]";
+        let email_mock = kratos_server
+            .mock(
+                "GET",
+                "/admin/identities?credentials_identifier=lol.lol@lol.io",
+            )
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(&body)
+            .expect(1)
+            .create_async()
+            .await;
+        let other_mock = kratos_server
+            .mock(
+                "GET",
+                "/admin/identities?credentials_identifier=other@lol.io",
+            )
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(body)
+            .expect(1)
+            .create_async()
+            .await;
+        let read_mock = mock_read(&mut kratos_server, IDENTITY_USER_ADMIN).await;
+        let identity = serde_json::from_str(IDENTITY_USER_ADMIN).unwrap();
+        let payload = vec![
+            data(&email, "222"),
+            data(&other, "334"),
+            data(&email, "334"),
+            data(&other, "222"),
+        ];
+        let (_, report) = update_controller(
+            Arc::new(config),
+            payload,
+            identity,
+            "project",
+            "1",
+            Operation::Add,
+            false,
+        )
+        .await
+        .unwrap();
+        read_mock.assert_async().await;
+        email_mock.assert_async().await;
+        other_mock.assert_async().await;
+        assert!(report
+            .items
+            .iter()
+            .all(|item| item.status == ItemStatus::Applied));
+    }
+
+    #[tokio::test]
+    async fn test_update_controler_rollback() {
+        let data = |resource: &str| Data {
+            id: IDType::Email(Email::from_str("lol.lol@lol.io").unwrap()),
+            ressource_type: "project".to_owned(),
+            ressource_id: resource.to_owned(),
+            value: Value::Array(vec![Value::String("admin".to_owned())]),
+        };
+        let mut kratos_server = MockServer::new_async().await;
+        let config = configure(Some(&kratos_server), None, None).await;
+        let body = "[".to_owned() + IDENTITY_USER_ADMIN + "]";
+        let kratos_mock = kratos_server
+            .mock(
+                "GET",
+                "/admin/identities?credentials_identifier=lol.lol@lol.io",
+            )
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(body)
+            .create_async()
+            .await;
+        let read_mock = mock_read(&mut kratos_server, IDENTITY_USER_ADMIN).await;
+        let mut identity: Value = serde_json::from_str(IDENTITY_USER_ADMIN).unwrap();
+        identity["metadata_public"]["project"][FAILING_RESOURCE] = json!(["admin"]);
+        let identity = serde_json::from_value(identity).unwrap();
+        let error = update_controller(
+            Arc::new(config),
+            vec![data("222"), data("334"), data(FAILING_RESOURCE)],
+            identity,
+            "project",
+            "1",
+            Operation::Add,
+            false,
+        )
+        .await
+        .unwrap_err();
+        read_mock.assert_async().await;
+        kratos_mock.assert_async().await;
+        let RouterError::MultiStatus(report) = RouterError::from(error) else {
+            panic!("the update was not rolled back");
+        };
+        let statuses: Vec<_> = report.items.iter().map(|item| item.status).collect();
+        assert_eq!(
+            statuses,
+            [
+                ItemStatus::Reverted,
+                ItemStatus::Reverted,
+                ItemStatus::Failed
+            ]
+        );
+        assert_eq!(report.items[2].code, 502);
+        assert!(report.items.iter().all(|item| item.identity.is_some()));
+    }
+
+    #[cfg(not(feature = "opa"))]
+    #[tokio::test]
+    async fn test_update_controler_organisation_group() {
+        let group = uuid::Uuid::parse_str("7113206d-afc0-41ad-bbca-b1e8113beb82").unwrap();
+        let data = Data {
+            id: IDType::ID(group),
+            ressource_type: "group".to_owned(),
+            ressource_id: "9f425a8d-7efc-4768-8f23-7647a74fdf13".to_owned(),
+            value: json!("awesome"),
+        };
+        let mut kratos_server = MockServer::new_async().await;
+        let config = configure(Some(&kratos_server), None, None).await;
+        let kratos_mock = kratos_server
+            .mock("GET", format!("/admin/identities/{group}").as_str())
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(crate::utils::test::IDENTITY_ORG_ADMIN)
+            .create_async()
+            .await;
+        let read_mock = mock_read(&mut kratos_server, crate::utils::test::IDENTITY_ORG_ADMIN).await;
+        let identity = serde_json::from_str(IDENTITY_USER_ADMIN).unwrap();
+        let (_, report) = update_controller(
+            Arc::new(config),
+            vec![data],
+            identity,
+            "organisation",
+            "1",
+            Operation::Add,
+            false,
+        )
+        .await
+        .unwrap();
+        read_mock.assert_async().await;
+        kratos_mock.assert_async().await;
+        assert_eq!(report.items[0].status, ItemStatus::Applied);
+    }
+
+    #[cfg(not(feature = "opa"))]
+    #[tokio::test]
+    async fn test_update_controler_denied() {
+        let data = |resource: &str| Data {
+            id: IDType::Email(Email::from_str("lol.lol@lol.io").unwrap()),
+            ressource_type: "project".to_owned(),
+            ressource_id: resource.to_owned(),
+            value: Value::Array(vec![Value::String("viewer".to_owned())]),
+        };
+        let config = configure(None, None, None).await;
+        let identity = serde_json::from_str(IDENTITY_USER_ADMIN).unwrap();
+        let error = update_controller(
+            Arc::new(config),
+            vec![data("222"), data("999")],
+            identity,
+            "project",
+            "1",
+            Operation::Add,
+            false,
+        )
+        .await
+        .unwrap_err();
+        let RouterError::MultiStatus(report) = RouterError::from(error) else {
+            panic!("the items were not reported");
+        };
+        assert_eq!(report.items[0].status, ItemStatus::Skipped);
+        assert_eq!(report.items[0].allowed, Some(true));
+        assert_eq!(report.items[1].status, ItemStatus::Denied);
+        assert_eq!(report.items[1].code, 403);
+        assert_eq!(report.items[1].allowed, Some(false));
+    }
+
+    #[cfg(not(feature = "opa"))]
+    #[tokio::test]
+    async fn test_update_controler_last_admin() {
+        let group = uuid::Uuid::parse_str("7113206d-afc0-41ad-bbca-b1e8113beb82").unwrap();
+        let data = Data {
+            id: IDType::ID(group),
+            ressource_type: "user".to_owned(),
+            ressource_id: "af25f904-5319-4011-95a4-343365d64811".to_owned(),
+            value: Value::Null,
+        };
+        let mut kratos_server = MockServer::new_async().await;
+        let config = configure(Some(&kratos_server), None, None).await;
+        let kratos_mock = kratos_server
+            .mock("GET", format!("/admin/identities/{group}").as_str())
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(IDENTITY_GROUP_ADMIN)
+            .expect(1)
+            .create_async()
+            .await;
+        let identity = serde_json::from_str(IDENTITY_USER_ADMIN).unwrap();
+        let error = update_controller(
+            Arc::new(config),
+            vec![data],
+            identity,
+            "groups",
+            "1",
+            Operation::Remove,
+            false,
+        )
+        .await
+        .unwrap_err();
+        kratos_mock.assert_async().await;
+        let RouterError::MultiStatus(report) = RouterError::from(error) else {
+            panic!("the items were not reported");
+        };
+        assert_eq!(report.items[0].status, ItemStatus::Failed);
+        assert_eq!(report.items[0].code, 409);
+        assert!(report.error.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_apply_to_iam_fresh_previous() {
+        let mut kratos_server = MockServer::new_async().await;
+        let config = Arc::new(configure(Some(&kratos_server), None, None).await);
+        // the identity changed since it was resolved, the revert restores its current value
+        let mut current: Value = serde_json::from_str(IDENTITY_USER_ADMIN).unwrap();
+        current["metadata_public"]["project"]["222"] = json!(["viewer"]);
+        let read_mock = mock_read(&mut kratos_server, &current.to_string()).await;
+        let resolved = Arc::new(serde_json::from_str(IDENTITY_USER_ADMIN).unwrap());
+        let data = Data {
+            id: IDType::Email(Email::from_str("lol.lol@lol.io").unwrap()),
+            ressource_type: "project".to_owned(),
+            ressource_id: "222".to_owned(),
+            value: json!(["admin"]),
+        };
+        let previous = apply_to_iam(resolved, config, data, Operation::Replace)
+            .await
+            .unwrap();
+        assert_eq!(previous, Previous::Value(json!(["viewer"])));
+        read_mock.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_previous_value() {
+        let config = configure(None, None, None).await;
+        let identity = serde_json::from_str(IDENTITY_USER_ADMIN).unwrap();
+        let data = |perm_type: &str, resource: &str| Data {
+            id: IDType::Email(Email::from_str("lol.lol@lol.io").unwrap()),
+            ressource_type: perm_type.to_owned(),
+            ressource_id: resource.to_owned(),
+            value: Value::Null,
+        };
+        assert_eq!(
+            previous_value(&config, &identity, &data("project", "222")),
+            Previous::Value(json!(["admin"]))
+        );
+        assert_eq!(
+            previous_value(&config, &identity, &data("project", "333")),
+            Previous::Unset
+        );
+        assert_eq!(
+            previous_value(&config, &identity, &data("organisation", "222")),
+            Previous::Unset
+        );
+        let group = serde_json::from_str(IDENTITY_GROUP_ADMIN).unwrap();
+        assert_eq!(
+            previous_value(&config, &group, &data("project", "122")),
+            Previous::Listed
+        );
+        assert_eq!(
+            previous_value(&config, &group, &data("project", "999")),
+            Previous::Unset
+        );
+    }
 }
@@ -1,10 +1,13 @@
 use axum::{
     http::{header::ToStrError, StatusCode},
     response::{IntoResponse, Response},
+    Json,
 };
 use thiserror::Error;
 use tracing::error;
 
+use crate::controller::update::MultiStatus;
+
 ///handler for error in the http service
 ///it convert the recevied error in a response
 #[derive(Error, Debug)]
@@ -12,39 +15,142 @@ pub enum RouterError {
     #[error("failed to serialize data.")]
     Serialisation(#[from] serde_json::Error),
     #[error("failed to apply identity patch.")]
-    Internal(#[from] anyhow::Error),
+    Internal(anyhow::Error),
     #[error("failled to convert to string.")]
     StrConvert(#[from] ToStrError),
     #[error("the request failed.")]
     Http(#[from] reqwest::Error),
     #[error("extract error.")]
     Status(StatusCode),
+    #[error("the role {0} can not be granted by the caller.")]
+    Escalation(String),
+    #[error("the change would leave {0} without admin.")]
+    LastAdmin(String),
+    #[error("the update failed: {}", .0.error.as_deref().unwrap_or_default())]
+    MultiStatus(MultiStatus),
+    #[error("{0} was modified by a concurrent update.")]
+    Conflict(String),
+}
+
+impl RouterError {
+    /// Get the http status code of the response of the error.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            RouterError::Serialisation(_)
+            | RouterError::Internal(_)
+            | RouterError::StrConvert(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            RouterError::Http(_) => StatusCode::SERVICE_UNAVAILABLE,
+            RouterError::Status(status) => *status,
+            RouterError::Escalation(_) => StatusCode::FORBIDDEN,
+            RouterError::LastAdmin(_) | RouterError::Conflict(_) => StatusCode::CONFLICT,
+            RouterError::MultiStatus(_) => StatusCode::MULTI_STATUS,
+        }
+    }
+}
+
+/// Convert an anyhow error to a router error, keeping the router errors returned by the
+/// controllers.
+impl From<anyhow::Error> for RouterError {
+    fn from(error: anyhow::Error) -> Self {
+        match error.downcast::<RouterError>() {
+            Ok(error) => error,
+            Err(error) => RouterError::Internal(error),
+        }
+    }
 }
 
 #[cfg(not(tarpaulin_include))]
 impl IntoResponse for RouterError {
     fn into_response(self) -> Response {
+        let status = self.status();
         match self {
             RouterError::Serialisation(e) => {
                 error!("{:?}", e);
-                (StatusCode::INTERNAL_SERVER_ERROR, "INTERNAL_SERVER_ERROR").into_response()
+                (status, "INTERNAL_SERVER_ERROR").into_response()
             }
             RouterError::Internal(e) => {
                 error!("{:?}", e);
-                (StatusCode::INTERNAL_SERVER_ERROR, "INTERNAL_SERVER_ERROR").into_response()
+                (status, "INTERNAL_SERVER_ERROR").into_response()
             }
             RouterError::StrConvert(e) => {
                 error!("{:?}, while converting str", e);
-                (StatusCode::INTERNAL_SERVER_ERROR, "INTERNAL_SERVER_ERROR").into_response()
+                (status, "INTERNAL_SERVER_ERROR").into_response()
             }
             RouterError::Status(e) => {
                 error!("status error: {:?}", e);
-                (e, format!("{:?}", e.canonical_reason())).into_response()
+                (status, format!("{:?}", e.canonical_reason())).into_response()
+            }
+            RouterError::Escalation(role) => {
+                error!("privilege escalation: {role}");
+                (
+                    status,
+                    format!("the role {role} is above the roles of the caller"),
+                )
+                    .into_response()
+            }
+            RouterError::LastAdmin(id) => {
+                error!("last admin of {id}");
+                (status, format!("the change would leave {id} without admin")).into_response()
+            }
+            RouterError::Conflict(id) => {
+                error!("concurrent update of {id}");
+                (
+                    status,
+                    format!("{id} was modified by a concurrent update, retry the request"),
+                )
+                    .into_response()
+            }
+            RouterError::MultiStatus(report) => {
+                error!(
+                    "update failed: {}",
+                    report.error.as_deref().unwrap_or_default()
+                );
+                (status, Json(report)).into_response()
             }
             RouterError::Http(e) => {
                 error!("http error: {:?}", e);
-                (StatusCode::SERVICE_UNAVAILABLE, "SERVICE_UNAVAILABLE").into_response()
+                (status, "SERVICE_UNAVAILABLE").into_response()
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test_error {
+    use anyhow::anyhow;
+
+    use super::*;
+
+    #[test]
+    fn test_from_anyhow() {
+        let error: anyhow::Error = RouterError::Status(StatusCode::FORBIDDEN).into();
+        assert!(matches!(
+            RouterError::from(error),
+            RouterError::Status(StatusCode::FORBIDDEN)
+        ));
+        assert!(matches!(
+            RouterError::from(anyhow!("error")),
+            RouterError::Internal(_)
+        ));
+    }
+
+    #[test]
+    fn test_status() {
+        assert_eq!(
+            RouterError::Status(StatusCode::NOT_FOUND).status(),
+            StatusCode::NOT_FOUND
+        );
+        assert_eq!(
+            RouterError::Escalation("owner".to_owned()).status(),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            RouterError::Conflict("group".to_owned()).status(),
+            StatusCode::CONFLICT
+        );
+        assert_eq!(
+            RouterError::Internal(anyhow!("error")).status(),
+            StatusCode::INTERNAL_SERVER_ERROR
+        );
+    }
+}
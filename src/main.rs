@@ -3,7 +3,7 @@ use std::{
     sync::Arc,
 };
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use axum::{
     http::HeaderName,
     routing::{get, post},
@@ -26,18 +26,29 @@ pub mod permission {
 }
 
 mod controller;
+use controller::{
+    migrate::{migrate_controller, Status},
+    reconcile::{reconcile_controller, reconcile_loop},
+};
 mod handelers;
 use handelers::{fallback, shutdown_signal, shutdown_signal_trigger};
 mod router;
 use router::{
-    alive, list_groups, list_projects, ready, update_groups, update_organisation, update_projects,
+    alive, check_identity_permission, check_permission, create_group, create_organisation,
+    delete_group, delete_organisation, get_group, get_job, get_organisation, list_groups,
+    list_members, list_projects, ready, update_groups, update_organisation, update_projects,
 };
 mod config;
 use config::{SiriusConfig, CONFIG_FALLBACK};
 
 use crate::router::list_orga;
 mod error;
+mod metadata;
 mod utils;
+use utils::{
+    index::rebuild_index,
+    queue::{drain_queue, run_queue, JobQueue},
+};
 
 type ConfigState = Arc<RwLock<SiriusConfig>>;
 
@@ -46,9 +57,37 @@ pub fn app(shared_state: ConfigState) -> Router {
     info!("configuring main router");
 
     let api_route = Router::new()
-        .route("/project", post(update_projects).get(list_projects))
-        .route("/group", post(update_groups).get(list_groups))
-        .route("/organisation", post(update_organisation).get(list_orga));
+        .route(
+            "/project",
+            post(update_projects)
+                .put(update_projects)
+                .delete(update_projects)
+                .get(list_projects),
+        )
+        .route(
+            "/group",
+            post(update_groups)
+                .put(update_groups)
+                .delete(update_groups)
+                .get(list_groups),
+        )
+        .route(
+            "/organisation",
+            post(update_organisation)
+                .put(update_organisation)
+                .delete(update_organisation)
+                .get(list_orga),
+        )
+        .route("/project/:id/members", get(list_members))
+        .route("/group/create", post(create_group))
+        .route("/group/:id", get(get_group).delete(delete_group))
+        .route("/organisation/create", post(create_organisation))
+        .route(
+            "/organisation/:id",
+            get(get_organisation).delete(delete_organisation),
+        )
+        .route("/jobs/:id", get(get_job))
+        .route("/check", get(check_permission));
 
     Router::new()
         .nest("/api/iam", api_route)
@@ -71,11 +110,13 @@ pub fn app(shared_state: ConfigState) -> Router {
 }
 
 ///heatlh router config
+///it also serve the permission check of a given identity, guarded by the check token.
 pub fn health(shared_state: ConfigState) -> Router {
     info!("configuring health router");
     Router::new()
         .route("/alive", get(alive))
         .route("/ready", get(ready))
+        .route("/check/:id", get(check_identity_permission))
         .fallback(fallback)
         .with_state(shared_state)
 }
@@ -99,6 +140,37 @@ where
     tokio::spawn(service)
 }
 
+/// Migrate the metadata of all the identities and print the report of each of them.
+#[cfg(not(tarpaulin_include))]
+async fn migrate(config: SiriusConfig, dry_run: bool) -> Result<()> {
+    let migrations = migrate_controller(Arc::new(config), dry_run).await?;
+    for migration in &migrations {
+        println!("{migration}");
+    }
+    let failed = migrations
+        .iter()
+        .filter(|migration| matches!(migration.status, Status::Failed { .. }))
+        .count();
+    if failed > 0 {
+        bail!("{failed} identities failed to migrate");
+    }
+    Ok(())
+}
+
+/// Reconcile the groups and organisations with their users and print the drifts found.
+#[cfg(not(tarpaulin_include))]
+async fn reconcile(config: SiriusConfig, id: Option<&str>, repair: bool) -> Result<()> {
+    let drifts = reconcile_controller(Arc::new(config), id, repair).await?;
+    for drift in &drifts {
+        println!("{drift}");
+    }
+    let failed = drifts.iter().filter(|drift| drift.error.is_some()).count();
+    if failed > 0 {
+        bail!("{failed} drifts failed to be repaired");
+    }
+    Ok(())
+}
+
 #[cfg(not(tarpaulin_include))]
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -111,8 +183,28 @@ async fn main() -> Result<()> {
         warn!("Config variable not found switching to fallback");
         CONFIG_FALLBACK.to_owned()
     });
-    let config = SiriusConfig::new(&config_path).await;
+    let mut config = SiriusConfig::new(&config_path).await;
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let flag = |name: &str| args.iter().any(|arg| arg == name);
+    match args.first().map(String::as_str) {
+        Some("migrate") => return migrate(config, flag("--dry-run")).await,
+        Some("reconcile") => {
+            let id = args
+                .iter()
+                .position(|arg| arg == "--id")
+                .and_then(|pos| args.get(pos + 1));
+            return reconcile(config, id.map(String::as_str), flag("--repair")).await;
+        }
+        _ => (),
+    }
+    config.queue = Some(Arc::new(JobQueue::open(&config.jobs.path)?));
     let service = config.service.clone();
+    let index_config = Arc::new(config.clone());
+    tokio::spawn(async move {
+        if let Err(e) = rebuild_index(index_config).await {
+            warn!("{e}");
+        }
+    });
     let shared_state = Arc::new(RwLock::new(config));
     tokio::spawn(init_watcher(config_path, shared_state.clone(), None));
     let (trigger, shutdown) = Tripwire::new();
@@ -120,11 +212,13 @@ async fn main() -> Result<()> {
     info!("statrting http router");
     let http_addr = service.addr.clone() + ":" + &service.ports.main as &str;
     let http = make_http(shared_state.clone(), app, http_addr, signal_sender).await;
+    tokio::spawn(reconcile_loop(shared_state.clone(), shutdown.clone()));
+    let worker = tokio::spawn(run_queue(shared_state.clone(), shutdown.clone()));
     let signal_receiver = shutdown_signal(shutdown);
     let health_addr = service.addr.clone() + ":" + &service.ports.health as &str;
     let health = make_http(shared_state.clone(), health, health_addr, signal_receiver).await;
     let (http_critical, health_critical) = tokio::try_join!(http, health)?;
     http_critical?;
     health_critical?;
-    Ok(())
+    drain_queue(&shared_state, worker).await
 }
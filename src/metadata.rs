@@ -0,0 +1,354 @@
+use std::{collections::HashMap, fmt};
+
+use anyhow::{anyhow, bail};
+use ory_kratos_client::models::Identity;
+use serde::{
+    de::{self, value::MapAccessDeserializer, MapAccess, SeqAccess, Visitor},
+    Deserialize, Deserializer, Serialize,
+};
+use serde_json::Value;
+
+use crate::config::SiriusConfig;
+
+/// Version of the permission metadata written by sirius.
+/// The identities without version are read as the first one.
+pub const METADATA_VERSION: u64 = 1;
+
+fn current_version() -> u64 {
+    METADATA_VERSION
+}
+
+/// Roles held on a resource, stored as a role or a list of roles.
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct Roles(pub Vec<String>);
+
+impl<'de> Deserialize<'de> for Roles {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct RolesVisitor;
+
+        impl<'de> Visitor<'de> for RolesVisitor {
+            type Value = Roles;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a role or a list of roles")
+            }
+
+            fn visit_str<E: de::Error>(self, role: &str) -> Result<Roles, E> {
+                Ok(Roles(vec![role.to_owned()]))
+            }
+
+            fn visit_unit<E: de::Error>(self) -> Result<Roles, E> {
+                Ok(Roles::default())
+            }
+
+            fn visit_none<E: de::Error>(self) -> Result<Roles, E> {
+                Ok(Roles::default())
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Roles, A::Error> {
+                let mut roles = Vec::new();
+                while let Some(role) = seq.next_element::<String>()? {
+                    roles.push(role);
+                }
+                Ok(Roles(roles))
+            }
+        }
+
+        deserializer.deserialize_any(RolesVisitor)
+    }
+}
+
+/// Id of a project, older identities store them as numbers.
+struct ProjectId(String);
+
+impl<'de> Deserialize<'de> for ProjectId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ProjectIdVisitor;
+
+        impl<'de> Visitor<'de> for ProjectIdVisitor {
+            type Value = ProjectId;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a project id as a string or a number")
+            }
+
+            fn visit_str<E: de::Error>(self, id: &str) -> Result<ProjectId, E> {
+                Ok(ProjectId(id.to_owned()))
+            }
+
+            fn visit_u64<E: de::Error>(self, id: u64) -> Result<ProjectId, E> {
+                Ok(ProjectId(id.to_string()))
+            }
+
+            fn visit_i64<E: de::Error>(self, id: i64) -> Result<ProjectId, E> {
+                Ok(ProjectId(id.to_string()))
+            }
+        }
+
+        deserializer.deserialize_any(ProjectIdVisitor)
+    }
+}
+
+/// Projects of an identity, either a list of ids or a map of the ids to the roles held.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum Projects {
+    Ids(Vec<String>),
+    Roles(HashMap<String, Roles>),
+}
+
+impl Default for Projects {
+    fn default() -> Self {
+        Projects::Ids(Vec::new())
+    }
+}
+
+impl Projects {
+    /// List the project ids.
+    pub fn ids(&self) -> Vec<String> {
+        match self {
+            Projects::Ids(ids) => ids.clone(),
+            Projects::Roles(roles) => roles.keys().cloned().collect(),
+        }
+    }
+
+    /// List the project ids with the roles held, no role is held on a list of ids.
+    pub fn roles(&self) -> Vec<(String, Vec<String>)> {
+        match self {
+            Projects::Ids(ids) => ids.iter().map(|id| (id.clone(), Vec::new())).collect(),
+            Projects::Roles(roles) => roles
+                .iter()
+                .map(|(id, roles)| (id.clone(), roles.0.clone()))
+                .collect(),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Projects {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ProjectsVisitor;
+
+        impl<'de> Visitor<'de> for ProjectsVisitor {
+            type Value = Projects;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a list of project ids or a map of project ids to roles")
+            }
+
+            fn visit_unit<E: de::Error>(self) -> Result<Projects, E> {
+                Ok(Projects::default())
+            }
+
+            fn visit_none<E: de::Error>(self) -> Result<Projects, E> {
+                Ok(Projects::default())
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Projects, A::Error> {
+                let mut ids = Vec::new();
+                while let Some(ProjectId(id)) = seq.next_element()? {
+                    ids.push(id);
+                }
+                Ok(Projects::Ids(ids))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Projects, A::Error> {
+                let mut roles = HashMap::new();
+                while let Some((id, role)) = map.next_entry::<String, Roles>()? {
+                    roles.insert(id, role);
+                }
+                Ok(Projects::Roles(roles))
+            }
+        }
+
+        deserializer.deserialize_any(ProjectsVisitor)
+    }
+}
+
+/// Group or organisation entry of an identity.
+/// The users hold the name, projects and roles, the organisations only the name of their groups.
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Entry {
+    pub name: String,
+    pub project: Projects,
+    pub role: Roles,
+}
+
+/// Fields of an entry stored as a map.
+#[derive(Deserialize)]
+struct EntryFields {
+    name: String,
+    #[serde(default)]
+    project: Projects,
+    #[serde(default)]
+    role: Roles,
+}
+
+impl<'de> Deserialize<'de> for Entry {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct EntryVisitor;
+
+        impl<'de> Visitor<'de> for EntryVisitor {
+            type Value = Entry;
+
+            fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+                formatter.write_str("a name or a map with a name, projects and roles")
+            }
+
+            fn visit_str<E: de::Error>(self, name: &str) -> Result<Entry, E> {
+                Ok(Entry {
+                    name: name.to_owned(),
+                    ..Default::default()
+                })
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<Entry, A::Error> {
+                let fields = EntryFields::deserialize(MapAccessDeserializer::new(map))?;
+                Ok(Entry {
+                    name: fields.name,
+                    project: fields.project,
+                    role: fields.role,
+                })
+            }
+        }
+
+        deserializer.deserialize_any(EntryVisitor)
+    }
+}
+
+/// Permission metadata of an identity, shared by the users, groups and organisations.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Metadata {
+    #[serde(default = "current_version")]
+    pub version: u64,
+    #[serde(default)]
+    pub project: Projects,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub group: HashMap<String, Entry>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub organisation: HashMap<String, Entry>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub user: HashMap<String, Roles>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent: Option<String>,
+}
+
+impl Default for Metadata {
+    fn default() -> Self {
+        Metadata {
+            version: METADATA_VERSION,
+            project: Projects::default(),
+            group: HashMap::new(),
+            organisation: HashMap::new(),
+            user: HashMap::new(),
+            parent: None,
+        }
+    }
+}
+
+impl Metadata {
+    /// Get the group or organisation entries.
+    pub fn entries(&self, data_type: &str) -> anyhow::Result<&HashMap<String, Entry>> {
+        match data_type {
+            "group" => Ok(&self.group),
+            "organisation" => Ok(&self.organisation),
+            _ => bail!("unknown data type: {data_type}"),
+        }
+    }
+}
+
+/// Get the raw metadata of the identity where the mode of the opa section of the config point to.
+pub fn raw_metadata<'a>(
+    config: &SiriusConfig,
+    identity: &'a Identity,
+) -> anyhow::Result<Option<&'a Value>> {
+    let meta = match &config.opa.mode as &str {
+        "admin" => &identity.metadata_admin,
+        "public" => &identity.metadata_public,
+        "trait" => &identity.traits,
+        _ => bail!("Invalid mode! please put a valid mode (admin, public or trait) in the config"),
+    };
+    Ok(meta.as_ref())
+}
+
+/// Parse the permission metadata of the identity, the errors point to the malformed field.
+pub fn parse_metadata(config: &SiriusConfig, identity: &Identity) -> anyhow::Result<Metadata> {
+    let Some(raw) = raw_metadata(config, identity)? else {
+        bail!("no metadata in identity {}!", identity.id)
+    };
+    let metadata: Metadata = serde_path_to_error::deserialize(raw).map_err(|e| {
+        anyhow!(
+            "invalid metadata in identity {} at {}: {}",
+            identity.id,
+            e.path(),
+            e.inner()
+        )
+    })?;
+    if metadata.version > METADATA_VERSION {
+        bail!(
+            "unsupported metadata version {} in identity {}",
+            metadata.version,
+            identity.id
+        );
+    }
+    Ok(metadata)
+}
+
+#[cfg(test)]
+mod test_metadata {
+    use serde_json::json;
+
+    use super::*;
+
+    fn parse(raw: Value) -> anyhow::Result<Metadata> {
+        serde_path_to_error::deserialize(&raw).map_err(|e| anyhow!("{}: {}", e.path(), e.inner()))
+    }
+
+    #[test]
+    fn test_parse_shapes() {
+        let metadata = parse(json!({
+            "project": [122, "334"],
+            "group": {
+                "7113206d-afc0-41ad-bbca-b1e8113beb82": "default",
+                "9f425a8d-7efc-4768-8f23-7647a74fdf13": {
+                    "name": "awesome",
+                    "project": {"456": "admin"},
+                    "role": ["admin"]
+                }
+            },
+            "user": {"af25f904-5319-4011-95a4-343365d64811": "admin"}
+        }))
+        .unwrap();
+        assert_eq!(metadata.version, METADATA_VERSION);
+        assert_eq!(metadata.project.ids(), vec!["122", "334"]);
+        assert_eq!(
+            metadata.group["7113206d-afc0-41ad-bbca-b1e8113beb82"].name,
+            "default"
+        );
+        let entry = &metadata.group["9f425a8d-7efc-4768-8f23-7647a74fdf13"];
+        assert_eq!(
+            entry.project.roles(),
+            vec![("456".to_owned(), vec!["admin".to_owned()])]
+        );
+        assert_eq!(
+            metadata.user["af25f904-5319-4011-95a4-343365d64811"],
+            Roles(vec!["admin".to_owned()])
+        );
+    }
+
+    #[test]
+    fn test_parse_error_path() {
+        let error = parse(json!({
+            "group": {
+                "7113206d-afc0-41ad-bbca-b1e8113beb82": {
+                    "name": "awesome",
+                    "role": [true]
+                }
+            }
+        }))
+        .unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("group.7113206d-afc0-41ad-bbca-b1e8113beb82.role[0]"));
+    }
+}
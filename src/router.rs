@@ -1,9 +1,14 @@
 use std::{fmt::Display, sync::Arc};
 
 use anyhow::anyhow;
-use axum::{extract::State, http::HeaderMap, http::StatusCode, response::Result, Json};
+use axum::{
+    extract::{Path, Query, State},
+    http::{header::AUTHORIZATION, HeaderMap, Method, StatusCode},
+    response::Result,
+    Json,
+};
 use axum_extra::extract::cookie::CookieJar;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_email::Email;
 use serde_json::Value;
 use tokio::sync::RwLock;
@@ -13,12 +18,27 @@ use uuid::Uuid;
 use crate::{
     config::SiriusConfig,
     controller::{
-        list::{list_controller, list_project_controller},
-        sync::{sync, sync_groups, sync_user, SyncMode},
-        update::update_controller,
+        diff::{diff_controller, item_patches, Diff},
+        list::{
+            check_controller, detail_controller, list_controller, list_project_controller,
+            list_roles_controller,
+        },
+        manage::{
+            create_group_controller, create_organisation_controller, delete_group_controller,
+            delete_organisation_controller,
+        },
+        sync::{groups_plan, sync, sync_groups, sync_plan, unsync_groups, SyncMode},
+        update::{
+            get_kind_identity, get_kratos_identity, update_controller, ItemResult, MultiStatus,
+            Operation,
+        },
     },
     error::RouterError,
-    utils::error::send_error,
+    utils::{
+        cache::{revalidate_session, validate_session},
+        error::send_error,
+        queue::{enqueue, JobReport},
+    },
 };
 
 /// Enum representing  the type of id to use to get the kratos identity.
@@ -49,19 +69,54 @@ pub struct Data {
     pub value: Value,
 }
 
+/// Structure representing the payload to create an organisation.
+#[derive(Deserialize, Debug)]
+pub struct NewOrganisation {
+    pub name: String,
+}
+
+/// Structure representing the payload to create a group.
+#[derive(Deserialize, Debug)]
+pub struct NewGroup {
+    pub name: String,
+    pub organisation: Uuid,
+}
+
+/// Structure representing the query of the update routes, nothing is sent to iam in dry run.
+#[derive(Deserialize, Debug, Default)]
+pub struct UpdateQuery {
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+/// Structure representing the permission check query.
+#[derive(Deserialize, Debug)]
+pub struct CheckQuery {
+    pub project: String,
+    pub role: String,
+}
+
+/// Structure representing the permission check response.
+#[derive(Serialize, Debug)]
+pub struct CheckResponse {
+    pub project: String,
+    pub role: String,
+    pub allowed: bool,
+}
+
 async fn update_organisation_handler(
     config: Arc<SiriusConfig>,
     cookies: CookieJar,
     payload: Vec<Data>,
     correlation_id: &str,
-) -> Result<(), RouterError> {
+    operation: Operation,
+    dry_run: bool,
+) -> Result<MultiStatus, RouterError> {
     let Some(kratos_cookie) = cookies.get("ory_kratos_session") else {
         error!("Kratos cookie not found");
         return Err(RouterError::Status(StatusCode::UNAUTHORIZED));
     };
-    let identity = config
-        .kratos
-        .validate_session(kratos_cookie)
+    let identity = revalidate_session(&config, kratos_cookie)
         .await
         .map_err(|_| RouterError::Status(StatusCode::UNAUTHORIZED))?;
     info!("identity validated");
@@ -71,47 +126,99 @@ async fn update_organisation_handler(
             users.push((data.ressource_id.clone(), data.value.clone()));
         }
     }
-    let identity = update_controller(
+    let planned = dry_run.then(|| payload.clone());
+    let (identity, mut report) = update_controller(
         config.clone(),
         payload,
         identity,
         "organisation",
         correlation_id,
+        operation,
+        dry_run,
     )
     .await?;
+    if let Some(payload) = planned {
+        let mut patches = item_patches(&payload, &report.items, operation);
+        if !users.is_empty() {
+            let (roles, mode): (Vec<_>, _) = match operation {
+                Operation::Add | Operation::Replace => (
+                    users
+                        .iter()
+                        .map(|(user, role)| (user.clone(), Some(role.clone())))
+                        .collect(),
+                    SyncMode::User(users),
+                ),
+                Operation::Remove => (
+                    users.iter().map(|(user, _)| (user.clone(), None)).collect(),
+                    SyncMode::RemoveUser(users.into_iter().map(|(user, _)| user).collect()),
+                ),
+            };
+            patches.extend(groups_plan(&config, &identity, &roles)?);
+            patches.extend(sync_plan(&config, &identity, mode)?);
+        }
+        report.diff = diff_controller(&config, &patches).await?;
+        return Ok(report);
+    }
     if !users.is_empty() {
-        info!("updating group!");
-        sync_groups(config.clone(), &identity, &users).await?;
-        let mode = SyncMode::User(users);
-        info!("updating user!");
-        sync(&config, identity, mode).await?;
+        match operation {
+            Operation::Add | Operation::Replace => {
+                info!("updating group!");
+                sync_groups(config.clone(), &identity, &users).await?;
+                let mode = SyncMode::User(users);
+                info!("updating user!");
+                sync(&config, &identity.id, mode).await?;
+            }
+            Operation::Remove => {
+                let users: Vec<String> = users.into_iter().map(|(user, _)| user).collect();
+                info!("removing users from group!");
+                unsync_groups(config.clone(), &identity, &users).await?;
+                let mode = SyncMode::RemoveUser(users);
+                info!("updating user!");
+                sync(&config, &identity.id, mode).await?;
+            }
+        }
     }
-    Ok(())
+    Ok(report)
 }
 
 /// This route is used to update the groups of an organization them sync the groups and the users.
+/// A POST add the permissions, a PUT replace them and a DELETE remove them.
+/// The result of each item of the payload is returned with a 207, with the diff of the
+/// metadata in dry run.
 #[tracing::instrument]
 #[axum_macros::debug_handler]
 pub async fn update_organisation(
     State(config): State<Arc<RwLock<SiriusConfig>>>,
+    method: Method,
+    Query(query): Query<UpdateQuery>,
     headers: HeaderMap,
     cookies: CookieJar,
     Json(payload): Json<Vec<Data>>,
-) -> Result<&'static str, RouterError> {
+) -> Result<(StatusCode, Json<MultiStatus>), RouterError> {
     info!("new request!");
     let correlation_id = headers
         .get("correlation_id")
         .ok_or_else(|| anyhow!("the request as no correlation id!"))?
         .to_str()?;
+    let operation = Operation::try_from(&method)?;
     let config = config.read().await.clone();
     let config = Arc::new(config);
-    if let Err(e) =
-        update_organisation_handler(config.clone(), cookies, payload, correlation_id).await
+    match update_organisation_handler(
+        config.clone(),
+        cookies,
+        payload,
+        correlation_id,
+        operation,
+        query.dry_run,
+    )
+    .await
     {
-        send_error(&config.kafka, "error", &e, correlation_id).await?;
-        return Err(e);
+        Ok(report) => Ok((StatusCode::MULTI_STATUS, Json(report))),
+        Err(e) => {
+            send_error(&config.kafka, "error", &e, correlation_id).await?;
+            Err(e)
+        }
     }
-    Ok("200")
 }
 
 async fn update_groups_handler(
@@ -119,17 +226,18 @@ async fn update_groups_handler(
     cookies: CookieJar,
     payload: Vec<Data>,
     correlation_id: &str,
-) -> Result<(), RouterError> {
+    operation: Operation,
+    dry_run: bool,
+) -> Result<Accepted, RouterError> {
     let Some(kratos_cookie) = cookies.get("ory_kratos_session") else {
         error!("Kratos cookie not found");
         return Err(RouterError::Status(StatusCode::UNAUTHORIZED));
     };
-    let identity = config
-        .kratos
-        .validate_session(kratos_cookie)
+    let identity = revalidate_session(&config, kratos_cookie)
         .await
         .map_err(|_| RouterError::Status(StatusCode::UNAUTHORIZED))?;
     info!("identity validated");
+    let owner = identity.id.clone();
     let mut users = Vec::new();
     let mut projects = Vec::new();
     for data in &payload {
@@ -142,53 +250,147 @@ async fn update_groups_handler(
     }
     info!("users: {users:?}");
     info!("project: {projects:?}");
-    let group =
-        update_controller(config.clone(), payload, identity, "groups", correlation_id).await?;
+    let planned = dry_run.then(|| payload.clone());
+    let (group, report) = update_controller(
+        config.clone(),
+        payload,
+        identity,
+        "groups",
+        correlation_id,
+        operation,
+        dry_run,
+    )
+    .await?;
     info!("group updated");
+    let mut modes = Vec::new();
     if !users.is_empty() {
-        let sync_mode = SyncMode::User(users);
-        info!("lauching users sync");
-        tokio::spawn(sync_user(
-            config.clone(),
-            group.clone(),
-            correlation_id.to_owned(),
-            sync_mode,
-        ));
+        modes.push(match operation {
+            Operation::Add | Operation::Replace => SyncMode::User(users),
+            Operation::Remove => {
+                SyncMode::RemoveUser(users.into_iter().map(|(user, _)| user).collect())
+            }
+        });
     }
     if !projects.is_empty() {
-        let sync_mode = SyncMode::Project(projects);
-        info!("lauching projects sync");
-        tokio::spawn(sync_user(
-            config,
-            group,
-            correlation_id.to_owned(),
-            sync_mode,
-        ));
+        modes.push(match operation {
+            Operation::Add | Operation::Replace => SyncMode::Project(projects),
+            Operation::Remove => SyncMode::RemoveProject(projects),
+        });
     }
-    Ok(())
+    let mut accepted = Accepted {
+        job_id: None,
+        items: report.items,
+        diff: Vec::new(),
+    };
+    if let Some(payload) = planned {
+        let mut patches = item_patches(&payload, &accepted.items, operation);
+        for mode in modes {
+            patches.extend(sync_plan(&config, &group, mode)?);
+        }
+        accepted.diff = diff_controller(&config, &patches).await?;
+        return Ok(accepted);
+    }
+    if !modes.is_empty() {
+        info!("queuing members sync");
+        let job = enqueue(&config, &group.id, &owner, correlation_id, modes).await?;
+        accepted.job_id = Some(job.id);
+    }
+    Ok(accepted)
+}
+
+/// Structure representing the response of an update whose sync is queued.
+#[derive(Serialize, Debug)]
+pub struct Accepted {
+    pub job_id: Option<String>,
+    pub items: Vec<ItemResult>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub diff: Vec<Diff>,
 }
 
-/// This route is used to update a group then sync the users groups and projects.
+/// This route is used to update a group then queue the sync of the users groups and projects.
+/// A POST add the permissions, a PUT replace them and a DELETE remove them.
+/// The id of the sync job is returned, its status is given by the jobs route.
+/// In dry run nothing is queued and the diff of the metadata, members included, is returned.
 #[tracing::instrument]
 #[axum_macros::debug_handler]
 pub async fn update_groups(
     State(config): State<Arc<RwLock<SiriusConfig>>>,
+    method: Method,
+    Query(query): Query<UpdateQuery>,
     headers: HeaderMap,
     cookies: CookieJar,
     Json(payload): Json<Vec<Data>>,
-) -> Result<&'static str, RouterError> {
+) -> Result<(StatusCode, Json<Accepted>), RouterError> {
     info!("new request!");
     let correlation_id = headers
         .get("correlation_id")
         .ok_or_else(|| anyhow!("the request as no correlation id!"))?
         .to_str()?;
+    let operation = Operation::try_from(&method)?;
     let config = config.read().await.clone();
     let config = Arc::new(config);
-    if let Err(e) = update_groups_handler(config.clone(), cookies, payload, correlation_id).await {
-        send_error(&config.kafka, "error", &e, correlation_id).await?;
-        return Err(e);
+    match update_groups_handler(
+        config.clone(),
+        cookies,
+        payload,
+        correlation_id,
+        operation,
+        query.dry_run,
+    )
+    .await
+    {
+        Ok(accepted) if query.dry_run => Ok((StatusCode::MULTI_STATUS, Json(accepted))),
+        Ok(accepted) => Ok((StatusCode::ACCEPTED, Json(accepted))),
+        Err(e) => {
+            send_error(&config.kafka, "error", &e, correlation_id).await?;
+            Err(e)
+        }
     }
-    Ok("200")
+}
+
+async fn get_job_handler(
+    config: &SiriusConfig,
+    cookies: CookieJar,
+    id: Uuid,
+) -> Result<String, RouterError> {
+    let Some(kratos_cookie) = cookies.get("ory_kratos_session") else {
+        error!("Kratos cookie not found");
+        return Err(RouterError::Status(StatusCode::UNAUTHORIZED));
+    };
+    let identity = validate_session(config, kratos_cookie).await?;
+    info!("identity validated");
+    let queue = config
+        .queue
+        .as_ref()
+        .ok_or_else(|| anyhow!("job queue not initialized"))?;
+    // the jobs of the other identities are reported as not found
+    match queue.get(&id.to_string())? {
+        Some(job) if job.owner == identity.id => Ok(serde_json::to_string(&JobReport::from(&job))?),
+        _ => Err(RouterError::Status(StatusCode::NOT_FOUND)),
+    }
+}
+
+///This route return the status of a sync job with the count of patched users and their errors.
+#[tracing::instrument]
+#[axum_macros::debug_handler]
+pub async fn get_job(
+    State(config): State<Arc<RwLock<SiriusConfig>>>,
+    Path(id): Path<Uuid>,
+    headers: HeaderMap,
+    cookies: CookieJar,
+) -> Result<String, RouterError> {
+    info!("new request!");
+    let correlation_id = headers
+        .get("correlation_id")
+        .ok_or_else(|| anyhow!("the request as no correlation id!"))?
+        .to_str()?;
+
+    let config = config.read().await.clone();
+    let ret = get_job_handler(&config, cookies, id).await;
+    if let Err(ref e) = ret {
+        send_error(&config.kafka, "error", e, correlation_id).await?;
+    }
+    ret
 }
 
 async fn update_projects_handler(
@@ -196,44 +398,74 @@ async fn update_projects_handler(
     cookies: CookieJar,
     payload: Vec<Data>,
     correlation_id: &str,
-) -> Result<(), RouterError> {
+    operation: Operation,
+    dry_run: bool,
+) -> Result<MultiStatus, RouterError> {
     let Some(kratos_cookie) = cookies.get("ory_kratos_session") else {
         error!("kratos cookie not found");
         return Err(RouterError::Status(StatusCode::UNAUTHORIZED));
     };
-    let identity = config
-        .kratos
-        .validate_session(kratos_cookie)
+    let identity = revalidate_session(&config, kratos_cookie)
         .await
         .map_err(|_| RouterError::Status(StatusCode::UNAUTHORIZED))?;
     info!("identity validated");
-    update_controller(config, payload, identity, "projects", correlation_id).await?;
-    Ok(())
+    let planned = dry_run.then(|| payload.clone());
+    let (_, mut report) = update_controller(
+        config.clone(),
+        payload,
+        identity,
+        "projects",
+        correlation_id,
+        operation,
+        dry_run,
+    )
+    .await?;
+    if let Some(payload) = planned {
+        let patches = item_patches(&payload, &report.items, operation);
+        report.diff = diff_controller(&config, &patches).await?;
+    }
+    Ok(report)
 }
 
 /// This route is used to update an user projects.
+/// A POST add the permissions, a PUT replace them and a DELETE remove them.
+/// The result of each item of the payload is returned with a 207, with the diff of the
+/// metadata in dry run.
 #[tracing::instrument]
 #[axum_macros::debug_handler]
 pub async fn update_projects(
     State(config): State<Arc<RwLock<SiriusConfig>>>,
+    method: Method,
+    Query(query): Query<UpdateQuery>,
     headers: HeaderMap,
     cookies: CookieJar,
     Json(payload): Json<Vec<Data>>,
-) -> Result<&'static str, RouterError> {
+) -> Result<(StatusCode, Json<MultiStatus>), RouterError> {
     info!("new request!");
     let correlation_id = headers
         .get("correlation_id")
         .ok_or_else(|| anyhow!("the request as no correlation id!"))?
         .to_str()?;
 
+    let operation = Operation::try_from(&method)?;
     let config = config.read().await.clone();
     let config = Arc::new(config);
-    if let Err(e) = update_projects_handler(config.clone(), cookies, payload, correlation_id).await
+    match update_projects_handler(
+        config.clone(),
+        cookies,
+        payload,
+        correlation_id,
+        operation,
+        query.dry_run,
+    )
+    .await
     {
-        send_error(&config.kafka, "error", &e, correlation_id).await?;
-        return Err(e);
+        Ok(report) => Ok((StatusCode::MULTI_STATUS, Json(report))),
+        Err(e) => {
+            send_error(&config.kafka, "error", &e, correlation_id).await?;
+            Err(e)
+        }
     }
-    Ok("200")
 }
 
 async fn list_projects_handler(
@@ -244,9 +476,9 @@ async fn list_projects_handler(
         error!("Kratos cookie not found");
         return Err(RouterError::Status(StatusCode::UNAUTHORIZED));
     };
-    let identity = config.kratos.validate_session(kratos_cookie).await?;
+    let identity = validate_session(config, kratos_cookie).await?;
     info!("identity validated");
-    let data = list_project_controller(identity, config).await?;
+    let data = list_project_controller(&identity, config).await?;
     let resp = serde_json::to_string(&data)?;
     Ok(resp)
 }
@@ -281,7 +513,7 @@ async fn list_groups_handler(
         error!("Kratos cookie not found");
         return Err(RouterError::Status(StatusCode::UNAUTHORIZED));
     };
-    let identity = config.kratos.validate_session(kratos_cookie).await?;
+    let identity = validate_session(config, kratos_cookie).await?;
     info!("identity validated");
     let data = list_controller(identity, "group", config).await?;
     let resp = serde_json::to_string(&data)?;
@@ -318,7 +550,7 @@ async fn list_orga_handler(
         error!("Kratos cookie not found");
         return Err(RouterError::Status(StatusCode::UNAUTHORIZED));
     };
-    let identity = config.kratos.validate_session(kratos_cookie).await?;
+    let identity = validate_session(config, kratos_cookie).await?;
     info!("identity validated");
     let data = list_controller(identity, "organisation", config).await?;
     let resp = serde_json::to_string(&data)?;
@@ -347,112 +579,657 @@ pub async fn list_orga(
     ret
 }
 
-pub async fn alive() -> Result<&'static str, RouterError> {
-    Ok("200")
+async fn check_handler(
+    config: &SiriusConfig,
+    cookies: CookieJar,
+    query: CheckQuery,
+) -> Result<String, RouterError> {
+    let Some(kratos_cookie) = cookies.get("ory_kratos_session") else {
+        error!("Kratos cookie not found");
+        return Err(RouterError::Status(StatusCode::UNAUTHORIZED));
+    };
+    let identity = validate_session(config, kratos_cookie).await?;
+    info!("identity validated");
+    let allowed = check_controller(&identity, &query.project, &query.role, config).await?;
+    let resp = CheckResponse {
+        project: query.project,
+        role: query.role,
+        allowed,
+    };
+    Ok(serde_json::to_string(&resp)?)
 }
 
-pub async fn ready(
+///This route check if the identity has the given role on a project, the roles are merged from
+///the project, group and organisation.
+#[tracing::instrument]
+#[axum_macros::debug_handler]
+pub async fn check_permission(
     State(config): State<Arc<RwLock<SiriusConfig>>>,
-) -> Result<&'static str, RouterError> {
-    let config = config.read().await;
-    let client = match &config.kratos.client {
-        Some(client) => client,
-        None => Err(anyhow!("Kratos client not initialized"))?,
-    };
-    let iam_service = &config.iam.service;
-    let addr = format!(
-        "http://{}:{}/api/iam/ready",
-        iam_service.addr, iam_service.ports.health
-    );
-    let response = client.client.get(addr).send().await?;
-    response.error_for_status()?;
-    Ok("200")
-}
+    headers: HeaderMap,
+    cookies: CookieJar,
+    Query(query): Query<CheckQuery>,
+) -> Result<String, RouterError> {
+    info!("new request!");
+    let correlation_id = headers
+        .get("correlation_id")
+        .ok_or_else(|| anyhow!("the request as no correlation id!"))?
+        .to_str()?;
 
-#[cfg(test)]
-mod http_router_test {
-    use std::sync::Arc;
+    let config = config.read().await.clone();
+    let ret = check_handler(&config, cookies, query).await;
+    if let Err(ref e) = ret {
+        send_error(&config.kafka, "error", e, correlation_id).await?;
+    }
+    ret
+}
 
-    use axum::{
-        body::Body,
-        http::{header, Method, Request, StatusCode},
+///This route check if the given identity has the given role on a project.
+///It is served on the health router and the caller must send the check token as a bearer,
+///the route is not found if no token is configured.
+#[tracing::instrument(skip(headers))]
+#[axum_macros::debug_handler]
+pub async fn check_identity_permission(
+    State(config): State<Arc<RwLock<SiriusConfig>>>,
+    Path(id): Path<Uuid>,
+    headers: HeaderMap,
+    Query(query): Query<CheckQuery>,
+) -> Result<String, RouterError> {
+    info!("new request!");
+    let config = config.read().await.clone();
+    if config.authorization.check_token.is_none() {
+        return Err(RouterError::Status(StatusCode::NOT_FOUND));
+    }
+    let token = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    if !token.is_some_and(|token| config.authorization.is_check_token(token)) {
+        error!("invalid check token");
+        return Err(RouterError::Status(StatusCode::UNAUTHORIZED));
+    }
+    let identity = get_kratos_identity(&config, &IDType::ID(id)).await?;
+    let allowed = check_controller(&identity, &query.project, &query.role, &config).await?;
+    let resp = CheckResponse {
+        project: query.project,
+        role: query.role,
+        allowed,
     };
-    use mockito::Server;
-    use ory_kratos_client::models::Session;
-    use serde_json::json;
-    use tokio::sync::RwLock;
-    use tower::ServiceExt;
+    Ok(serde_json::to_string(&resp)?)
+}
 
-    use crate::{
-        app, health,
-        utils::test::{configure, IDENTITY_GROUP, IDENTITY_ORG, IDENTITY_USER},
+async fn list_members_handler(
+    config: &SiriusConfig,
+    cookies: CookieJar,
+    project: &str,
+) -> Result<String, RouterError> {
+    let Some(kratos_cookie) = cookies.get("ory_kratos_session") else {
+        error!("Kratos cookie not found");
+        return Err(RouterError::Status(StatusCode::UNAUTHORIZED));
     };
+    let identity = validate_session(config, kratos_cookie).await?;
+    info!("identity validated");
+    let roles = list_roles_controller(&identity, config).await?;
+    if !roles.contains_key(project) {
+        error!("the identity has no access to the project {project}");
+        return Err(RouterError::Status(StatusCode::FORBIDDEN));
+    }
+    let members = config.index.members(project);
+    let resp = serde_json::to_string(&members)?;
+    Ok(resp)
+}
 
-    #[tokio::test]
-    async fn test_alive() {
-        let config = configure(None, None, None).await;
-        let config = Arc::new(RwLock::new(config));
-        let app = health(config);
-        let response = app
-            .oneshot(
-                Request::builder()
-                    .method(Method::GET)
-                    .uri("/alive")
-                    .body(Body::from("200"))
-                    .unwrap(),
-            )
-            .await
-            .unwrap();
-        assert_eq!(response.status(), StatusCode::OK);
+///This route list every identity having access to a project with their roles and where they
+///come from.
+#[tracing::instrument]
+#[axum_macros::debug_handler]
+pub async fn list_members(
+    State(config): State<Arc<RwLock<SiriusConfig>>>,
+    Path(project): Path<String>,
+    headers: HeaderMap,
+    cookies: CookieJar,
+) -> Result<String, RouterError> {
+    info!("new request!");
+    let correlation_id = headers
+        .get("correlation_id")
+        .ok_or_else(|| anyhow!("the request as no correlation id!"))?
+        .to_str()?;
+
+    let config = config.read().await.clone();
+    let ret = list_members_handler(&config, cookies, &project).await;
+    if let Err(ref e) = ret {
+        send_error(&config.kafka, "error", e, correlation_id).await?;
     }
-    #[tokio::test]
-    async fn test_ready() {
-        let mut iam_server = Server::new_async().await;
+    ret
+}
 
-        let config = configure(None, None, Some(&iam_server)).await;
-        let iam_mock = iam_server
-            .mock("get", "/api/iam/ready")
-            .with_status(200)
-            .create_async()
-            .await;
-        let config = Arc::new(RwLock::new(config));
-        let app = health(config);
-        let response = app
-            .oneshot(
-                Request::builder()
-                    .method(Method::GET)
-                    .uri("/ready")
-                    .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
-                    .header("Cookie", "ory_kratos_session=bonjour")
-                    .body(Body::from("200"))
-                    .unwrap(),
-            )
-            .await
-            .unwrap();
-        iam_mock.assert_async().await;
-        assert_eq!(response.status(), StatusCode::OK);
+async fn detail_handler(
+    config: &SiriusConfig,
+    cookies: CookieJar,
+    id: Uuid,
+    kind: &str,
+) -> Result<String, RouterError> {
+    let Some(kratos_cookie) = cookies.get("ory_kratos_session") else {
+        error!("Kratos cookie not found");
+        return Err(RouterError::Status(StatusCode::UNAUTHORIZED));
+    };
+    let identity = validate_session(config, kratos_cookie).await?;
+    info!("identity validated");
+    let object = get_kind_identity(config, id, kind).await?;
+    let details = detail_controller(&object, config).await?;
+    if !details.user.contains_key(&identity.id) {
+        error!("the identity is not a member of {id}");
+        return Err(RouterError::Status(StatusCode::FORBIDDEN));
     }
+    let resp = serde_json::to_string(&details)?;
+    Ok(resp)
+}
 
-    #[tokio::test]
-    async fn test_update_users() {
-        let mut kratos_server = Server::new_async().await;
-        let opa_server = Server::new_async().await;
-        let config = configure(Some(&kratos_server), Some(&opa_server), None).await;
-        let body = "[".to_owned() + IDENTITY_USER + "]";
-        let session = Session::new(
-            "bonjour".to_owned(),
-            serde_json::from_str(IDENTITY_USER).unwrap(),
-        );
-        let kratos_mock_admin = kratos_server
-            .mock(
-                "get",
-                "/admin/identities?credentials_identifier=lol.lol@lol.io",
-            )
-            .with_status(200)
-            .with_header("content-type", "application/json")
+/// Return the details of a group or an organisation, an identity of another kind is not
+/// found.
+async fn get_detail(
+    config: Arc<RwLock<SiriusConfig>>,
+    id: Uuid,
+    headers: HeaderMap,
+    cookies: CookieJar,
+    kind: &str,
+) -> Result<String, RouterError> {
+    info!("new request!");
+    let correlation_id = headers
+        .get("correlation_id")
+        .ok_or_else(|| anyhow!("the request as no correlation id!"))?
+        .to_str()?;
+
+    let config = config.read().await.clone();
+    let ret = detail_handler(&config, cookies, id, kind).await;
+    if let Err(ref e) = ret {
+        send_error(&config.kafka, "error", e, correlation_id).await?;
+    }
+    ret
+}
+
+///This route return the name, projects, users and sub-groups of a group.
+#[tracing::instrument]
+#[axum_macros::debug_handler]
+pub async fn get_group(
+    State(config): State<Arc<RwLock<SiriusConfig>>>,
+    Path(id): Path<Uuid>,
+    headers: HeaderMap,
+    cookies: CookieJar,
+) -> Result<String, RouterError> {
+    get_detail(config, id, headers, cookies, "group").await
+}
+
+///This route return the name, projects, users and groups of an organisation.
+#[tracing::instrument]
+#[axum_macros::debug_handler]
+pub async fn get_organisation(
+    State(config): State<Arc<RwLock<SiriusConfig>>>,
+    Path(id): Path<Uuid>,
+    headers: HeaderMap,
+    cookies: CookieJar,
+) -> Result<String, RouterError> {
+    get_detail(config, id, headers, cookies, "organisation").await
+}
+
+async fn create_organisation_handler(
+    config: Arc<SiriusConfig>,
+    cookies: CookieJar,
+    payload: NewOrganisation,
+) -> Result<String, RouterError> {
+    let Some(kratos_cookie) = cookies.get("ory_kratos_session") else {
+        error!("Kratos cookie not found");
+        return Err(RouterError::Status(StatusCode::UNAUTHORIZED));
+    };
+    let identity = revalidate_session(&config, kratos_cookie).await?;
+    info!("identity validated");
+    let created = create_organisation_controller(config, &identity, &payload.name).await?;
+    let resp = serde_json::to_string(&created)?;
+    Ok(resp)
+}
+
+/// This route is used to create an organisation with its default group.
+#[tracing::instrument]
+#[axum_macros::debug_handler]
+pub async fn create_organisation(
+    State(config): State<Arc<RwLock<SiriusConfig>>>,
+    headers: HeaderMap,
+    cookies: CookieJar,
+    Json(payload): Json<NewOrganisation>,
+) -> Result<String, RouterError> {
+    info!("new request!");
+    let correlation_id = headers
+        .get("correlation_id")
+        .ok_or_else(|| anyhow!("the request as no correlation id!"))?
+        .to_str()?;
+    let config = config.read().await.clone();
+    let config = Arc::new(config);
+    let ret = create_organisation_handler(config.clone(), cookies, payload).await;
+    if let Err(ref e) = ret {
+        send_error(&config.kafka, "error", e, correlation_id).await?;
+    }
+    ret
+}
+
+async fn delete_organisation_handler(
+    config: Arc<SiriusConfig>,
+    cookies: CookieJar,
+    id: Uuid,
+) -> Result<(), RouterError> {
+    let Some(kratos_cookie) = cookies.get("ory_kratos_session") else {
+        error!("Kratos cookie not found");
+        return Err(RouterError::Status(StatusCode::UNAUTHORIZED));
+    };
+    let identity = revalidate_session(&config, kratos_cookie).await?;
+    info!("identity validated");
+    delete_organisation_controller(config, &identity, id).await?;
+    Ok(())
+}
+
+/// This route is used to delete an organisation, its groups and remove them from their users.
+#[tracing::instrument]
+#[axum_macros::debug_handler]
+pub async fn delete_organisation(
+    State(config): State<Arc<RwLock<SiriusConfig>>>,
+    Path(id): Path<Uuid>,
+    headers: HeaderMap,
+    cookies: CookieJar,
+) -> Result<&'static str, RouterError> {
+    info!("new request!");
+    let correlation_id = headers
+        .get("correlation_id")
+        .ok_or_else(|| anyhow!("the request as no correlation id!"))?
+        .to_str()?;
+    let config = config.read().await.clone();
+    let config = Arc::new(config);
+    if let Err(e) = delete_organisation_handler(config.clone(), cookies, id).await {
+        send_error(&config.kafka, "error", &e, correlation_id).await?;
+        return Err(e);
+    }
+    Ok("200")
+}
+
+async fn create_group_handler(
+    config: Arc<SiriusConfig>,
+    cookies: CookieJar,
+    payload: NewGroup,
+) -> Result<String, RouterError> {
+    let Some(kratos_cookie) = cookies.get("ory_kratos_session") else {
+        error!("Kratos cookie not found");
+        return Err(RouterError::Status(StatusCode::UNAUTHORIZED));
+    };
+    let identity = revalidate_session(&config, kratos_cookie).await?;
+    info!("identity validated");
+    let created =
+        create_group_controller(config, &identity, &payload.name, payload.organisation).await?;
+    let resp = serde_json::to_string(&created)?;
+    Ok(resp)
+}
+
+/// This route is used to create a group in an organisation.
+#[tracing::instrument]
+#[axum_macros::debug_handler]
+pub async fn create_group(
+    State(config): State<Arc<RwLock<SiriusConfig>>>,
+    headers: HeaderMap,
+    cookies: CookieJar,
+    Json(payload): Json<NewGroup>,
+) -> Result<String, RouterError> {
+    info!("new request!");
+    let correlation_id = headers
+        .get("correlation_id")
+        .ok_or_else(|| anyhow!("the request as no correlation id!"))?
+        .to_str()?;
+    let config = config.read().await.clone();
+    let config = Arc::new(config);
+    let ret = create_group_handler(config.clone(), cookies, payload).await;
+    if let Err(ref e) = ret {
+        send_error(&config.kafka, "error", e, correlation_id).await?;
+    }
+    ret
+}
+
+async fn delete_group_handler(
+    config: Arc<SiriusConfig>,
+    cookies: CookieJar,
+    id: Uuid,
+) -> Result<(), RouterError> {
+    let Some(kratos_cookie) = cookies.get("ory_kratos_session") else {
+        error!("Kratos cookie not found");
+        return Err(RouterError::Status(StatusCode::UNAUTHORIZED));
+    };
+    let identity = revalidate_session(&config, kratos_cookie).await?;
+    info!("identity validated");
+    delete_group_controller(config, &identity, id).await?;
+    Ok(())
+}
+
+/// This route is used to delete a group and remove it from its users and organisation.
+#[tracing::instrument]
+#[axum_macros::debug_handler]
+pub async fn delete_group(
+    State(config): State<Arc<RwLock<SiriusConfig>>>,
+    Path(id): Path<Uuid>,
+    headers: HeaderMap,
+    cookies: CookieJar,
+) -> Result<&'static str, RouterError> {
+    info!("new request!");
+    let correlation_id = headers
+        .get("correlation_id")
+        .ok_or_else(|| anyhow!("the request as no correlation id!"))?
+        .to_str()?;
+    let config = config.read().await.clone();
+    let config = Arc::new(config);
+    if let Err(e) = delete_group_handler(config.clone(), cookies, id).await {
+        send_error(&config.kafka, "error", &e, correlation_id).await?;
+        return Err(e);
+    }
+    Ok("200")
+}
+
+pub async fn alive() -> Result<&'static str, RouterError> {
+    Ok("200")
+}
+
+pub async fn ready(
+    State(config): State<Arc<RwLock<SiriusConfig>>>,
+) -> Result<&'static str, RouterError> {
+    let config = config.read().await;
+    let client = match &config.kratos.client {
+        Some(client) => client,
+        None => Err(anyhow!("Kratos client not initialized"))?,
+    };
+    let iam_service = &config.iam.service;
+    let addr = format!(
+        "http://{}:{}/api/iam/ready",
+        iam_service.addr, iam_service.ports.health
+    );
+    let response = client.client.get(addr).send().await?;
+    response.error_for_status()?;
+    Ok("200")
+}
+
+#[cfg(test)]
+mod http_router_test {
+    use std::sync::Arc;
+
+    use axum::{
+        body::{to_bytes, Body},
+        http::{header, Method, Request, StatusCode},
+    };
+    use mockito::Server;
+    use ory_kratos_client::models::Session;
+    use serde_json::{json, Value};
+    use tokio::sync::RwLock;
+    use tower::ServiceExt;
+
+    use crate::{
+        app, health,
+        utils::{
+            index::index_identity,
+            test::{
+                configure, IDENTITY_GROUP, IDENTITY_GROUP_ADMIN, IDENTITY_ORG, IDENTITY_USER,
+                IDENTITY_USER_ADMIN,
+            },
+        },
+    };
+
+    #[tokio::test]
+    async fn test_alive() {
+        let config = configure(None, None, None).await;
+        let config = Arc::new(RwLock::new(config));
+        let app = health(config);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(Method::GET)
+                    .uri("/alive")
+                    .body(Body::from("200"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+    #[tokio::test]
+    async fn test_ready() {
+        let mut iam_server = Server::new_async().await;
+
+        let config = configure(None, None, Some(&iam_server)).await;
+        let iam_mock = iam_server
+            .mock("get", "/api/iam/ready")
+            .with_status(200)
+            .create_async()
+            .await;
+        let config = Arc::new(RwLock::new(config));
+        let app = health(config);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(Method::GET)
+                    .uri("/ready")
+                    .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                    .header("Cookie", "ory_kratos_session=bonjour")
+                    .body(Body::from("200"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        iam_mock.assert_async().await;
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_check() {
+        let mut kratos_server = Server::new_async().await;
+        let config = configure(Some(&kratos_server), None, None).await;
+        let session = Session::new(
+            "bonjour".to_owned(),
+            serde_json::from_str(IDENTITY_USER_ADMIN).unwrap(),
+        );
+        let kratos_mock_session = kratos_server
+            .mock("get", "/sessions/whoami")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(serde_json::to_string(&session).unwrap())
+            .create_async()
+            .await;
+        let config = Arc::new(RwLock::new(config));
+        let app = app(config);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(Method::GET)
+                    .uri("/api/iam/check?project=122&role=admin")
+                    .header("correlation_id", "1")
+                    .header("Cookie", "ory_kratos_session=bonjour")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        kratos_mock_session.assert_async().await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["allowed"], json!(true));
+    }
+
+    #[tokio::test]
+    async fn test_check_identity() {
+        let mut kratos_server = Server::new_async().await;
+        let mut config = configure(Some(&kratos_server), None, None).await;
+        config.authorization.check_token = Some("secret".to_owned());
+        let kratos_mock_admin = kratos_server
+            .mock(
+                "get",
+                "/admin/identities/9f425a8d-7efc-4768-8f23-7647a74fdf13",
+            )
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(IDENTITY_USER_ADMIN)
+            .create_async()
+            .await;
+        let config = Arc::new(RwLock::new(config));
+        let app = health(config.clone());
+        let check = |token: Option<&str>| {
+            let mut request = Request::builder()
+                .method(Method::GET)
+                .uri("/check/9f425a8d-7efc-4768-8f23-7647a74fdf13?project=122&role=owner");
+            if let Some(token) = token {
+                request = request.header("Authorization", format!("Bearer {token}"));
+            }
+            request.body(Body::empty()).unwrap()
+        };
+        let response = app.clone().oneshot(check(Some("secret"))).await.unwrap();
+        kratos_mock_admin.assert_async().await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["allowed"], json!(false));
+        for token in [None, Some("wrong")] {
+            let response = app.clone().oneshot(check(token)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+        // without a token the route is disabled
+        config.write().await.authorization.check_token = None;
+        let response = app.oneshot(check(Some("secret"))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_list_members() {
+        let mut kratos_server = Server::new_async().await;
+        let config = configure(Some(&kratos_server), None, None).await;
+        let identity = serde_json::from_str(IDENTITY_USER_ADMIN).unwrap();
+        index_identity(&config, &identity).await;
+        let session = Session::new("bonjour".to_owned(), identity);
+        let kratos_mock_session = kratos_server
+            // the session is cached after the first request
+            .mock("get", "/sessions/whoami")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(serde_json::to_string(&session).unwrap())
+            .expect(1)
+            .create_async()
+            .await;
+        let config = Arc::new(RwLock::new(config));
+        let response = app(config.clone())
+            .oneshot(
+                Request::builder()
+                    .method(Method::GET)
+                    .uri("/api/iam/project/122/members")
+                    .header("correlation_id", "1")
+                    .header("Cookie", "ory_kratos_session=bonjour")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body[0]["id"], json!("af25f904-5319-4011-95a4-343365d64811"));
+        assert_eq!(body[0]["source"]["type"], json!("group"));
+        let response = app(config)
+            .oneshot(
+                Request::builder()
+                    .method(Method::GET)
+                    .uri("/api/iam/project/999/members")
+                    .header("correlation_id", "1")
+                    .header("Cookie", "ory_kratos_session=bonjour")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        kratos_mock_session.assert_async().await;
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_get_group() {
+        let mut kratos_server = Server::new_async().await;
+        let config = configure(Some(&kratos_server), None, None).await;
+        let session = Session::new(
+            "bonjour".to_owned(),
+            serde_json::from_str(IDENTITY_USER_ADMIN).unwrap(),
+        );
+        let mut group: Value = serde_json::from_str(IDENTITY_GROUP_ADMIN).unwrap();
+        group["schema_id"] = json!("group");
+        let kratos_mock_session = kratos_server
+            .mock("get", "/sessions/whoami")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(serde_json::to_string(&session).unwrap())
+            .create_async()
+            .await;
+        let kratos_mock_admin = kratos_server
+            .mock(
+                "get",
+                "/admin/identities/9f425a8d-7efc-4768-8f23-7647a74fdf13",
+            )
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(group.to_string())
+            .create_async()
+            .await;
+        let config = Arc::new(RwLock::new(config));
+        let request = |kind: &str| {
+            Request::builder()
+                .method(Method::GET)
+                .uri(format!(
+                    "/api/iam/{kind}/9f425a8d-7efc-4768-8f23-7647a74fdf13"
+                ))
+                .header("correlation_id", "1")
+                .header("Cookie", "ory_kratos_session=bonjour")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let response = app(config.clone()).oneshot(request("group")).await.unwrap();
+        kratos_mock_session.assert_async().await;
+        kratos_mock_admin.assert_async().await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["project"], json!(["122", "334", "456"]));
+        // a group is not an organisation
+        let response = app(config).oneshot(request("organisation")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_update_users() {
+        let mut kratos_server = Server::new_async().await;
+        let opa_server = Server::new_async().await;
+        let config = configure(Some(&kratos_server), Some(&opa_server), None).await;
+        let body = "[".to_owned() + IDENTITY_USER + "]";
+        let session = Session::new(
+            "bonjour".to_owned(),
+            // the caller needs a role on the resource it updates
+            serde_json::from_str(IDENTITY_USER_ADMIN).unwrap(),
+        );
+        let kratos_mock_admin = kratos_server
+            .mock(
+                "get",
+                "/admin/identities?credentials_identifier=lol.lol@lol.io",
+            )
+            .with_status(200)
+            .with_header("content-type", "application/json")
             .with_body(body)
             .create_async()
             .await;
+        // the writes read the identity again, bypassing the cache
+        let kratos_mock_read = kratos_server
+            .mock(
+                "get",
+                "/admin/identities/af25f904-5319-4011-95a4-343365d64811",
+            )
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(IDENTITY_USER)
+            .expect_at_least(1)
+            .create_async()
+            .await;
         let kratos_mock_session = kratos_server
             .mock("get", "/sessions/whoami")
             .with_status(200)
@@ -490,9 +1267,122 @@ mod http_router_test {
             .await
             .unwrap();
         kratos_mock_session.assert_async().await;
+        kratos_mock_read.assert_async().await;
         kratos_mock_admin.assert_async().await;
         // opa_mock.assert_async().await;
-        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.status(), StatusCode::MULTI_STATUS);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["items"][0]["status"], "applied");
+        assert_eq!(body["items"][0]["code"], 200);
+    }
+
+    #[tokio::test]
+    async fn test_remove_users() {
+        let mut kratos_server = Server::new_async().await;
+        let opa_server = Server::new_async().await;
+        let config = configure(Some(&kratos_server), Some(&opa_server), None).await;
+        let body = "[".to_owned() + IDENTITY_USER_ADMIN + "]";
+        let session = Session::new(
+            "bonjour".to_owned(),
+            serde_json::from_str(IDENTITY_USER_ADMIN).unwrap(),
+        );
+        let kratos_mock_admin = kratos_server
+            .mock(
+                "get",
+                "/admin/identities?credentials_identifier=lol.lol@lol.io",
+            )
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(body)
+            .create_async()
+            .await;
+        let kratos_mock_session = kratos_server
+            .mock("get", "/sessions/whoami")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(serde_json::to_string(&session).unwrap())
+            .create_async()
+            .await;
+        let config = Arc::new(RwLock::new(config));
+        let app = app(config);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(Method::DELETE)
+                    .uri("/api/iam/project")
+                    .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                    .header("Cookie", "ory_kratos_session=bonjour")
+                    .body(Body::from(
+                        serde_json::to_string(&json!([{
+                          "id": "lol.lol@lol.io",
+                          "type": "project",
+                          "ressource_id": "222",
+                          "value": ["contributor"]
+                        }]))
+                        .unwrap(),
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        kratos_mock_session.assert_async().await;
+        kratos_mock_admin.assert_async().await;
+        assert_eq!(response.status(), StatusCode::MULTI_STATUS);
+    }
+
+    #[tokio::test]
+    async fn test_replace_users() {
+        let mut kratos_server = Server::new_async().await;
+        let opa_server = Server::new_async().await;
+        let config = configure(Some(&kratos_server), Some(&opa_server), None).await;
+        let body = "[".to_owned() + IDENTITY_USER_ADMIN + "]";
+        let session = Session::new(
+            "bonjour".to_owned(),
+            serde_json::from_str(IDENTITY_USER_ADMIN).unwrap(),
+        );
+        let kratos_mock_admin = kratos_server
+            .mock(
+                "get",
+                "/admin/identities?credentials_identifier=lol.lol@lol.io",
+            )
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(body)
+            .create_async()
+            .await;
+        let kratos_mock_session = kratos_server
+            .mock("get", "/sessions/whoami")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(serde_json::to_string(&session).unwrap())
+            .create_async()
+            .await;
+        let config = Arc::new(RwLock::new(config));
+        let app = app(config);
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(Method::PUT)
+                    .uri("/api/iam/project")
+                    .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                    .header("Cookie", "ory_kratos_session=bonjour")
+                    .body(Body::from(
+                        serde_json::to_string(&json!([{
+                          "id": "lol.lol@lol.io",
+                          "type": "project",
+                          "ressource_id": "222",
+                          "value": ["viewer"]
+                        }]))
+                        .unwrap(),
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        kratos_mock_session.assert_async().await;
+        kratos_mock_admin.assert_async().await;
+        assert_eq!(response.status(), StatusCode::MULTI_STATUS);
     }
 
     #[tokio::test]
@@ -502,7 +1392,8 @@ mod http_router_test {
         let config = configure(Some(&kratos_server), Some(&opa_server), None).await;
         let session = Session::new(
             "bonjour".to_owned(),
-            serde_json::from_str(IDENTITY_USER).unwrap(),
+            // the caller needs a role on the resource it updates
+            serde_json::from_str(IDENTITY_USER_ADMIN).unwrap(),
         );
         let kratos_mock_admin = kratos_server
             .mock(
@@ -514,11 +1405,25 @@ mod http_router_test {
             .with_body(IDENTITY_GROUP)
             .create_async()
             .await;
+        // the writes read the identity again, bypassing the cache
+        let kratos_mock_read = kratos_server
+            .mock(
+                "get",
+                "/admin/identities/af25f904-5319-4011-95a4-343365d64811",
+            )
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(IDENTITY_GROUP)
+            .expect_at_least(1)
+            .create_async()
+            .await;
+        // the session is cached until the update writes the caller identity
         let kratos_mock_session = kratos_server
             .mock("get", "/sessions/whoami")
             .with_status(200)
             .with_header("content-type", "application/json")
             .with_body(serde_json::to_string(&session).unwrap())
+            .expect(2)
             .create_async()
             .await;
         /* let opa_mock = opa_server
@@ -531,6 +1436,7 @@ mod http_router_test {
         let config = Arc::new(RwLock::new(config));
         let app = app(config);
         let response = app
+            .clone()
             .oneshot(
                 Request::builder()
                     .method(Method::POST)
@@ -551,11 +1457,129 @@ mod http_router_test {
             )
             .await
             .unwrap();
-        kratos_mock_session.assert_async().await;
+        kratos_mock_read.assert_async().await;
         kratos_mock_admin.assert_async().await;
         // opa_mock.assert_async().await;
         println!("{:#?}", response);
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        let job_id = body["job_id"].as_str().unwrap();
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .method(Method::GET)
+                    .uri(format!("/api/iam/jobs/{job_id}"))
+                    .header("Cookie", "ory_kratos_session=bonjour")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
         assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["id"], job_id);
+        assert_eq!(body["status"], "pending");
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .method(Method::GET)
+                    .uri(format!("/api/iam/jobs/{}", uuid::Uuid::new_v4()))
+                    .header("Cookie", "ory_kratos_session=bonjour")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        kratos_mock_session.assert_async().await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_update_group_dry_run() {
+        let mut kratos_server = Server::new_async().await;
+        let config = configure(Some(&kratos_server), None, None).await;
+        let session = Session::new(
+            "bonjour".to_owned(),
+            serde_json::from_str(IDENTITY_USER_ADMIN).unwrap(),
+        );
+        let kratos_mock_group = kratos_server
+            .mock(
+                "get",
+                "/admin/identities/9f425a8d-7efc-4768-8f23-7647a74fdf13",
+            )
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(IDENTITY_GROUP_ADMIN)
+            .create_async()
+            .await;
+        // the group fixture holds the id of the user fixture
+        let kratos_mock_snapshot = kratos_server
+            .mock(
+                "get",
+                "/admin/identities/af25f904-5319-4011-95a4-343365d64811",
+            )
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(IDENTITY_GROUP_ADMIN)
+            .create_async()
+            .await;
+        let kratos_mock_user = kratos_server
+            .mock("get", "/admin/identities/222")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(IDENTITY_USER_ADMIN)
+            .create_async()
+            .await;
+        let kratos_mock_session = kratos_server
+            .mock("get", "/sessions/whoami")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(serde_json::to_string(&session).unwrap())
+            .create_async()
+            .await;
+        let config = Arc::new(RwLock::new(config));
+        let queue = config.read().await.queue.clone().unwrap();
+        let response = app(config)
+            .oneshot(
+                Request::builder()
+                    .method(Method::POST)
+                    .uri("/api/iam/group?dry_run=true")
+                    .header(header::CONTENT_TYPE, mime::APPLICATION_JSON.as_ref())
+                    .header("Cookie", "ory_kratos_session=bonjour")
+                    .body(Body::from(
+                        serde_json::to_string(&json!([{
+                          "id": "9f425a8d-7efc-4768-8f23-7647a74fdf13",
+                          "type": "user",
+                          "ressource_id": "222",
+                          "value": ["contributor"]
+                        }]))
+                        .unwrap(),
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        kratos_mock_session.assert_async().await;
+        kratos_mock_group.assert_async().await;
+        kratos_mock_snapshot.assert_async().await;
+        kratos_mock_user.assert_async().await;
+        assert_eq!(response.status(), StatusCode::MULTI_STATUS);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["job_id"], Value::Null);
+        assert_eq!(body["items"][0]["status"], "planned");
+        let diff = body["diff"].as_array().unwrap();
+        assert_eq!(diff.len(), 2);
+        assert_eq!(diff[0]["id"], "222");
+        assert_eq!(
+            diff[0]["after"]["group"]["af25f904-5319-4011-95a4-343365d64811"]["role"],
+            json!(["contributor"])
+        );
+        assert_eq!(diff[1]["after"]["user"]["222"], json!(["contributor"]));
+        assert!(queue.pending().unwrap().is_empty());
     }
 
     #[tokio::test]
@@ -565,7 +1589,8 @@ mod http_router_test {
         let config = configure(Some(&kratos_server), Some(&opa_server), None).await;
         let session = Session::new(
             "bonjour".to_owned(),
-            serde_json::from_str(IDENTITY_USER).unwrap(),
+            // the caller needs a role on the resource it updates
+            serde_json::from_str(IDENTITY_USER_ADMIN).unwrap(),
         );
         let kratos_mock_admin = kratos_server
             .mock(
@@ -577,6 +1602,18 @@ mod http_router_test {
             .with_body(IDENTITY_ORG)
             .create_async()
             .await;
+        // the organisation read back by the sync of its users
+        let _kratos_mock_sync = kratos_server
+            .mock(
+                "get",
+                "/admin/identities/af25f904-5319-4011-95a4-343365d64811",
+            )
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(IDENTITY_ORG)
+            .expect_at_least(1)
+            .create_async()
+            .await;
         let kratos_mock_session = kratos_server
             .mock("get", "/sessions/whoami")
             .with_status(200)
@@ -618,6 +1655,6 @@ mod http_router_test {
         kratos_mock_admin.assert_async().await;
         // opa_mock.assert_async().await;
         println!("{:#?}", response);
-        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.status(), StatusCode::MULTI_STATUS);
     }
 }
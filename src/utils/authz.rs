@@ -0,0 +1,556 @@
+use std::collections::HashMap;
+
+use anyhow::Result;
+use axum::http::StatusCode;
+use ory_kratos_client::models::Identity;
+use serde::de::DeserializeOwned;
+#[cfg(not(feature = "opa"))]
+use tracing::debug;
+use tracing::error;
+
+use crate::{
+    config::SiriusConfig,
+    controller::{
+        list::{detail_controller, list_controller, list_roles_controller},
+        update::{get_kratos_identity, Operation},
+    },
+    error::RouterError,
+    metadata::{Entry, Roles},
+    router::{Data, IDType},
+};
+
+/// List the permission types an endpoint may write.
+fn writable_types(endpoint: &str) -> &'static [&'static str] {
+    match endpoint {
+        "project" | "projects" => &["project"],
+        "group" | "groups" => &["project", "user"],
+        "organisation" => &["group", "project", "user"],
+        _ => &[],
+    }
+}
+
+/// Check that the value of an item parses as the entry stored for its type.
+fn parses_as<T: DeserializeOwned>(data: &Data) -> Result<()> {
+    if let Err(e) = serde_json::from_value::<T>(data.value.clone()) {
+        error!("invalid {} value for {}: {e}", data.ressource_type, data.id);
+        Err(
+            anyhow::Error::from(RouterError::Status(StatusCode::BAD_REQUEST))
+                .context(format!("invalid {} value: {e}", data.ressource_type)),
+        )?;
+    }
+    Ok(())
+}
+
+/// Check that the endpoint may write the type of the item and that its value has the shape
+/// of the entry stored for it: roles for the users and projects, a name or a group entry for
+/// the groups. The value of a removal is not written so it is not checked.
+pub fn ensure_writable(endpoint: &str, data: &Data, operation: Operation) -> Result<()> {
+    if !writable_types(endpoint).contains(&data.ressource_type.as_str()) {
+        error!(
+            "{} can not be written through {endpoint}",
+            data.ressource_type
+        );
+        Err(
+            anyhow::Error::from(RouterError::Status(StatusCode::FORBIDDEN)).context(format!(
+                "{} can not be written through {endpoint}",
+                data.ressource_type
+            )),
+        )?;
+    }
+    if operation == Operation::Remove {
+        return Ok(());
+    }
+    match data.ressource_type.as_str() {
+        "group" => parses_as::<Entry>(data),
+        _ => parses_as::<Roles>(data),
+    }
+}
+
+/// Extract the roles held by the caller on the group or organisation.
+/// The organisations are synced in the group metadata of their users so both are looked up.
+async fn membership_roles(
+    config: &SiriusConfig,
+    caller: &Identity,
+    data_types: &[&str],
+    id: &str,
+) -> Result<Vec<String>> {
+    let mut roles = Vec::new();
+    for data_type in data_types {
+        let memberships = match list_controller(caller.clone(), data_type, config).await {
+            Ok(memberships) => memberships,
+            Err(e) => {
+                error!("no {data_type} role readable for {}: {e}", caller.id);
+                continue;
+            }
+        };
+        if let Some(membership) = memberships.get(id) {
+            roles.extend(membership.role.iter().cloned());
+        }
+    }
+    Ok(roles)
+}
+
+/// Extract the roles held by the caller on a project, none when its metadata can not be read.
+async fn project_roles(config: &SiriusConfig, caller: &Identity, project: &str) -> Vec<String> {
+    match list_roles_controller(caller, config).await {
+        Ok(mut roles) => roles
+            .remove(project)
+            .map(|roles| roles.into_iter().collect())
+            .unwrap_or_default(),
+        Err(e) => {
+            error!("no project role readable for {}: {e}", caller.id);
+            Vec::new()
+        }
+    }
+}
+
+/// Extract the roles held by the caller on the resource targeted by the endpoint.
+/// A caller whose metadata can not be read holds no role.
+pub async fn caller_roles(
+    config: &SiriusConfig,
+    caller: &Identity,
+    endpoint: &str,
+    data: &Data,
+) -> Result<Vec<String>> {
+    let roles = match endpoint {
+        "project" | "projects" => project_roles(config, caller, &data.ressource_id).await,
+        "group" | "groups" => match data.id {
+            IDType::ID(ref id) => {
+                membership_roles(config, caller, &["group"], &id.to_string()).await?
+            }
+            IDType::Email(_) => Vec::new(),
+        },
+        "organisation" => match data.id {
+            IDType::ID(ref id) => {
+                membership_roles(config, caller, &["organisation", "group"], &id.to_string())
+                    .await?
+            }
+            IDType::Email(_) => Vec::new(),
+        },
+        _ => {
+            error!("unknown endpoint: {endpoint}");
+            Vec::new()
+        }
+    };
+    Ok(roles)
+}
+
+/// Extract the roles granted by an item: its value for the users and projects, a role or a list
+/// of roles, and the role of the entry for the groups and organisations.
+/// The items whose roles can not be read are rejected rather than left unchecked.
+fn granted_roles(data: &Data) -> Result<Vec<String>> {
+    let roles = match data.ressource_type.as_str() {
+        "user" | "project" => serde_json::from_value::<Roles>(data.value.clone()),
+        "group" | "organisation" => {
+            serde_json::from_value::<Entry>(data.value.clone()).map(|entry| entry.role)
+        }
+        perm_type => {
+            error!("the roles granted by {perm_type} can not be ranked");
+            Err(RouterError::Status(StatusCode::BAD_REQUEST))?
+        }
+    };
+    match roles {
+        Ok(Roles(roles)) => Ok(roles),
+        Err(e) => {
+            error!("invalid {} value for {}: {e}", data.ressource_type, data.id);
+            Err(
+                anyhow::Error::from(RouterError::Status(StatusCode::BAD_REQUEST))
+                    .context(format!("invalid {} value: {e}", data.ressource_type)),
+            )
+        }
+    }
+}
+
+/// Check that the roles granted by the payload are not above the highest role the caller hold
+/// on the resource targeted by the endpoint.
+pub async fn ensure_grantable(
+    config: &SiriusConfig,
+    caller: &Identity,
+    endpoint: &str,
+    data: &Data,
+) -> Result<()> {
+    let granted = granted_roles(data)?;
+    if granted.is_empty() {
+        return Ok(());
+    }
+    let roles = caller_roles(config, caller, endpoint, data).await?;
+    for role in granted {
+        if !config.authorization.can_grant(&roles, &role) {
+            error!(
+                "{} can not grant {role} on {endpoint} {}",
+                caller.id, data.id
+            );
+            Err(RouterError::Escalation(role))?;
+        }
+    }
+    Ok(())
+}
+
+/// Check that the groups and organisations targeted by the payload keep at least one admin in
+/// their user metadata once the removals and replacements are applied.
+/// Each error is returned with the indexes of the payload items changing the identity.
+pub async fn ensure_admin_left(
+    config: &SiriusConfig,
+    endpoint: &str,
+    payload: &[Data],
+    operation: Operation,
+) -> Vec<(Vec<usize>, anyhow::Error)> {
+    if operation == Operation::Add || !matches!(endpoint, "group" | "groups" | "organisation") {
+        return Vec::new();
+    }
+    let mut targets: HashMap<String, Vec<usize>> = HashMap::new();
+    for (index, data) in payload.iter().enumerate() {
+        if data.ressource_type == "user" {
+            targets.entry(data.id.to_string()).or_default().push(index);
+        }
+    }
+    let mut errors = Vec::new();
+    for indexes in targets.into_values() {
+        let changes: Vec<&Data> = indexes.iter().map(|index| &payload[*index]).collect();
+        if let Err(e) = ensure_admin_changes(config, &changes, operation).await {
+            errors.push((indexes, e));
+        }
+    }
+    errors.sort_by_key(|(indexes, _)| indexes[0]);
+    errors
+}
+
+/// Check that the changes of the users of one group or organisation keep an admin.
+async fn ensure_admin_changes(
+    config: &SiriusConfig,
+    changes: &[&Data],
+    operation: Operation,
+) -> Result<()> {
+    let identity = get_kratos_identity(config, &changes[0].id).await?;
+    let before = detail_controller(&identity, config).await?.user;
+    let mut users = before.clone();
+    for data in changes {
+        match operation {
+            Operation::Remove => {
+                users.remove(&data.ressource_id);
+            }
+            _ => {
+                users.insert(data.ressource_id.clone(), granted_roles(data)?);
+            }
+        }
+    }
+    ensure_admin_kept(config, &identity.id, &before, &users)
+}
+
+/// Check that a group or organisation whose users held an admin role before a change still
+/// has one of them after it.
+pub fn ensure_admin_kept(
+    config: &SiriusConfig,
+    id: &str,
+    before: &HashMap<String, Vec<String>>,
+    after: &HashMap<String, Vec<String>>,
+) -> Result<()> {
+    let has_admin = |users: &HashMap<String, Vec<String>>| {
+        users
+            .values()
+            .any(|roles| config.authorization.is_admin(roles))
+    };
+    if has_admin(before) && !has_admin(after) {
+        error!("the change would leave {id} without admin");
+        Err(RouterError::LastAdmin(id.to_owned()))?;
+    }
+    Ok(())
+}
+
+/// Check that removing the users from a group or organisation keeps one of its admins.
+pub fn ensure_removal_allowed(
+    config: &SiriusConfig,
+    id: &str,
+    users: &HashMap<String, Vec<String>>,
+    removed: &[String],
+) -> Result<()> {
+    let kept = users
+        .iter()
+        .filter(|(user, _)| !removed.contains(user))
+        .map(|(user, roles)| (user.clone(), roles.clone()))
+        .collect();
+    ensure_admin_kept(config, id, users, &kept)
+}
+
+/// Check that the caller hold an admin role on the project, required to link it to a group or
+/// an organisation.
+#[cfg(not(feature = "opa"))]
+async fn project_admin(config: &SiriusConfig, caller: &Identity, project: &str) -> bool {
+    let roles = project_roles(config, caller, project).await;
+    config.authorization.is_admin(&roles)
+}
+
+/// Check from its own metadata that the caller hold a role allowing the operation on the
+/// resource targeted by the endpoint. The projects linked to a group or an organisation also
+/// require an admin role on them.
+#[cfg(not(feature = "opa"))]
+pub async fn authorize(
+    config: &SiriusConfig,
+    caller: &Identity,
+    endpoint: &str,
+    data: &Data,
+    operation: Operation,
+) -> Result<bool> {
+    let roles = caller_roles(config, caller, endpoint, data).await?;
+    let mut allowed = config.authorization.allows(&roles, operation.method());
+    if allowed
+        && data.ressource_type == "project"
+        && matches!(endpoint, "group" | "groups" | "organisation")
+    {
+        allowed = project_admin(config, caller, &data.ressource_id).await;
+    }
+    debug!(
+        "{} {:?} on {endpoint} {}: {allowed}",
+        caller.id, operation, data.id
+    );
+    Ok(allowed)
+}
+
+#[cfg(test)]
+mod test_authz {
+    use mockito::Server as MockServer;
+    use serde_email::Email;
+    use serde_json::{json, Value};
+    use uuid::Uuid;
+
+    use super::*;
+    use crate::utils::test::{configure, IDENTITY_GROUP_ADMIN, IDENTITY_USER_ADMIN};
+
+    fn data(id: IDType, ressource_id: &str) -> Data {
+        Data {
+            id,
+            ressource_type: "user".to_owned(),
+            ressource_id: ressource_id.to_owned(),
+            value: Value::Array(vec![Value::String("admin".to_owned())]),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ensure_grantable() {
+        let config = configure(None, None, None).await;
+        let caller = serde_json::from_str(IDENTITY_USER_ADMIN).unwrap();
+        let id = IDType::Email(Email::from_str("lol.lol@lol.io").unwrap());
+        let mut data = data(id, "222");
+        ensure_grantable(&config, &caller, "projects", &data)
+            .await
+            .unwrap();
+        data.value = Value::String("owner".to_owned());
+        let error = ensure_grantable(&config, &caller, "projects", &data)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            RouterError::from(error),
+            RouterError::Escalation(role) if role == "owner"
+        ));
+        data.ressource_id = "999".to_owned();
+        data.value = Value::Array(vec![Value::String("viewer".to_owned())]);
+        assert!(ensure_grantable(&config, &caller, "projects", &data)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_ensure_grantable_entry() {
+        let config = configure(None, None, None).await;
+        let caller = serde_json::from_str(IDENTITY_USER_ADMIN).unwrap();
+        let organisation = Uuid::parse_str("7113206d-afc0-41ad-bbca-b1e8113beb82").unwrap();
+        let mut data = Data {
+            id: IDType::ID(organisation),
+            ressource_type: "group".to_owned(),
+            ressource_id: "9f425a8d-7efc-4768-8f23-7647a74fdf13".to_owned(),
+            value: json!({"name": "awesome", "role": ["admin"]}),
+        };
+        ensure_grantable(&config, &caller, "organisation", &data)
+            .await
+            .unwrap();
+        data.value = json!({"name": "awesome", "role": ["owner"]});
+        let error = ensure_grantable(&config, &caller, "organisation", &data)
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            RouterError::from(error),
+            RouterError::Escalation(role) if role == "owner"
+        ));
+        // the values and types whose roles can not be read are not let through
+        data.value = json!(3);
+        assert!(ensure_grantable(&config, &caller, "organisation", &data)
+            .await
+            .is_err());
+        data.ressource_type = "test".to_owned();
+        data.value = json!("awesome");
+        assert!(ensure_grantable(&config, &caller, "organisation", &data)
+            .await
+            .is_err());
+    }
+
+    #[test]
+    fn test_ensure_writable() {
+        let id = IDType::Email(Email::from_str("lol.lol@lol.io").unwrap());
+        let status = |error: anyhow::Error| match RouterError::from(error) {
+            RouterError::Status(status) => status,
+            error => panic!("unexpected error: {error}"),
+        };
+        let mut project = data(id.clone(), "222");
+        project.ressource_type = "project".to_owned();
+        ensure_writable("projects", &project, Operation::Add).unwrap();
+        // a group entry granting projects can not be written through the project endpoint
+        let group = Data {
+            id,
+            ressource_type: "group".to_owned(),
+            ressource_id: "222".to_owned(),
+            value: json!({"name": "x", "project": ["999"], "role": ["owner"]}),
+        };
+        let error = ensure_writable("projects", &group, Operation::Add).unwrap_err();
+        assert_eq!(status(error), StatusCode::FORBIDDEN);
+        ensure_writable("organisation", &group, Operation::Add).unwrap();
+        let mut invalid = project.clone();
+        invalid.value = json!({"role": "owner"});
+        let error = ensure_writable("projects", &invalid, Operation::Add).unwrap_err();
+        assert_eq!(status(error), StatusCode::BAD_REQUEST);
+        ensure_writable("projects", &invalid, Operation::Remove).unwrap();
+        let mut invalid = group;
+        invalid.value = json!(["owner"]);
+        assert!(ensure_writable("organisation", &invalid, Operation::Replace).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_ensure_admin_left() {
+        let mut kratos_server = MockServer::new_async().await;
+        let config = configure(Some(&kratos_server), None, None).await;
+        let group = Uuid::parse_str("af25f904-5319-4011-95a4-343365d64811").unwrap();
+        // the group is read once and cached for the next checks
+        let mock_kratos = kratos_server
+            .mock("GET", format!("/admin/identities/{group}").as_str())
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(IDENTITY_GROUP_ADMIN)
+            .expect(1)
+            .create_async()
+            .await;
+        let last = [data(IDType::ID(group), &group.to_string())];
+        let mut errors = ensure_admin_left(&config, "groups", &last, Operation::Remove).await;
+        let (indexes, error) = errors.pop().unwrap();
+        assert!(errors.is_empty());
+        assert_eq!(indexes, vec![0]);
+        assert!(matches!(
+            RouterError::from(error),
+            RouterError::LastAdmin(_)
+        ));
+        let mut demoted = last[0].clone();
+        demoted.value = Value::String("viewer".to_owned());
+        let other = data(IDType::ID(group), "7113206d-afc0-41ad-bbca-b1e8113beb82");
+        // the items changing the same identity are reported together
+        let mut viewer = other.clone();
+        viewer.value = demoted.value.clone();
+        let errors =
+            ensure_admin_left(&config, "groups", &[viewer, demoted], Operation::Replace).await;
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].0, vec![0, 1]);
+        assert!(
+            ensure_admin_left(&config, "groups", &[other], Operation::Remove)
+                .await
+                .is_empty()
+        );
+        assert!(ensure_admin_left(&config, "groups", &last, Operation::Add)
+            .await
+            .is_empty());
+        assert!(
+            ensure_admin_left(&config, "projects", &last, Operation::Remove)
+                .await
+                .is_empty()
+        );
+        mock_kratos.assert_async().await;
+    }
+
+    #[cfg(not(feature = "opa"))]
+    #[tokio::test]
+    async fn test_authorize_project() {
+        let config = configure(None, None, None).await;
+        let caller = serde_json::from_str(IDENTITY_USER_ADMIN).unwrap();
+        let id = IDType::Email(Email::from_str("lol.lol@lol.io").unwrap());
+        assert!(authorize(
+            &config,
+            &caller,
+            "projects",
+            &data(id.clone(), "222"),
+            Operation::Add
+        )
+        .await
+        .unwrap());
+        assert!(!authorize(
+            &config,
+            &caller,
+            "projects",
+            &data(id, "999"),
+            Operation::Add
+        )
+        .await
+        .unwrap());
+    }
+
+    #[cfg(not(feature = "opa"))]
+    #[tokio::test]
+    async fn test_authorize_unreadable_caller() {
+        let config = configure(None, None, None).await;
+        let mut caller: Identity = serde_json::from_str(IDENTITY_USER_ADMIN).unwrap();
+        caller.metadata_public = Some(json!({"project": true}));
+        let id = IDType::Email(Email::from_str("lol.lol@lol.io").unwrap());
+        assert!(!authorize(
+            &config,
+            &caller,
+            "projects",
+            &data(id, "222"),
+            Operation::Add
+        )
+        .await
+        .unwrap());
+    }
+
+    #[cfg(not(feature = "opa"))]
+    #[tokio::test]
+    async fn test_authorize_group() {
+        let config = configure(None, None, None).await;
+        let caller = serde_json::from_str(IDENTITY_USER_ADMIN).unwrap();
+        let group = Uuid::parse_str("9f425a8d-7efc-4768-8f23-7647a74fdf13").unwrap();
+        assert!(authorize(
+            &config,
+            &caller,
+            "groups",
+            &data(IDType::ID(group), "user"),
+            Operation::Remove
+        )
+        .await
+        .unwrap());
+        assert!(!authorize(
+            &config,
+            &caller,
+            "groups",
+            &data(IDType::ID(Uuid::nil()), "user"),
+            Operation::Remove
+        )
+        .await
+        .unwrap());
+    }
+
+    #[cfg(not(feature = "opa"))]
+    #[tokio::test]
+    async fn test_authorize_group_project() {
+        let config = configure(None, None, None).await;
+        let caller = serde_json::from_str(IDENTITY_USER_ADMIN).unwrap();
+        let group = Uuid::parse_str("9f425a8d-7efc-4768-8f23-7647a74fdf13").unwrap();
+        let project = |ressource_id: &str| Data {
+            ressource_type: "project".to_owned(),
+            ..data(IDType::ID(group), ressource_id)
+        };
+        assert!(
+            authorize(&config, &caller, "groups", &project("222"), Operation::Add)
+                .await
+                .unwrap()
+        );
+        assert!(
+            !authorize(&config, &caller, "groups", &project("999"), Operation::Add)
+                .await
+                .unwrap()
+        );
+    }
+}
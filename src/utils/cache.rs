@@ -0,0 +1,292 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Result};
+use axum_extra::extract::cookie::Cookie;
+use ory_kratos_client::models::{Identity, Session};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tracing::debug;
+
+use crate::config::SiriusConfig;
+
+/// Structure representing a cached identity with the instant it expires.
+#[derive(Debug)]
+struct Entry {
+    identity: Arc<Identity>,
+    expires: Instant,
+}
+
+/// Identities cached under a key, the oldest entry is evicted when the capacity is reached.
+#[derive(Default, Debug)]
+struct Entries {
+    entries: HashMap<String, Entry>,
+}
+
+impl Entries {
+    fn get(&mut self, key: &str) -> Option<Arc<Identity>> {
+        let entry = self.entries.get(key)?;
+        if entry.expires <= Instant::now() {
+            self.entries.remove(key);
+            return None;
+        }
+        Some(entry.identity.clone())
+    }
+
+    fn insert(&mut self, key: String, identity: Arc<Identity>, ttl: Duration, capacity: usize) {
+        if capacity == 0 {
+            return;
+        }
+        let now = Instant::now();
+        if self.entries.len() >= capacity && !self.entries.contains_key(&key) {
+            self.entries.retain(|_, entry| entry.expires > now);
+            if self.entries.len() >= capacity {
+                let oldest = self
+                    .entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.expires)
+                    .map(|(key, _)| key.clone());
+                if let Some(oldest) = oldest {
+                    self.entries.remove(&oldest);
+                }
+            }
+        }
+        let expires = now + ttl;
+        self.entries.insert(key, Entry { identity, expires });
+    }
+
+    fn remove(&mut self, key: &str) {
+        self.entries.remove(key);
+    }
+
+    fn invalidate(&mut self, id: &str) {
+        self.entries.retain(|_, entry| entry.identity.id != id);
+    }
+}
+
+/// Cache of the sessions and identities read from kratos.
+/// The sessions are indexed by their cookie and the identities by their id or email, the
+/// entries of an identity are dropped when sirius writes it.
+#[derive(Default, Debug)]
+pub struct KratosCache {
+    sessions: Mutex<Entries>,
+    identities: Mutex<Entries>,
+}
+
+impl KratosCache {
+    /// Drop the session and identity entries of an identity.
+    pub fn invalidate(&self, id: &str) {
+        debug!("invalidating the cached entries of {id}");
+        for entries in [&self.sessions, &self.identities] {
+            entries
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .invalidate(id);
+        }
+    }
+
+    /// Number of cached sessions and identities, expired or not.
+    pub fn len(&self) -> usize {
+        [&self.sessions, &self.identities]
+            .iter()
+            .map(|entries| {
+                let entries = entries.lock().unwrap_or_else(|e| e.into_inner());
+                entries.entries.len()
+            })
+            .sum()
+    }
+
+    /// Check if nothing is cached.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Get the entry of a key or fetch it and cache it for `ttl`, or less if the fetched entry
+/// expires before, the cache is bypassed when the ttl is 0.
+async fn cached<F>(
+    entries: &Mutex<Entries>,
+    key: &str,
+    ttl: u64,
+    capacity: usize,
+    fetch: F,
+) -> Result<Identity>
+where
+    F: Future<Output = Result<(Identity, Option<Duration>)>>,
+{
+    if ttl == 0 {
+        return Ok(fetch.await?.0);
+    }
+    let hit = entries.lock().unwrap_or_else(|e| e.into_inner()).get(key);
+    if let Some(identity) = hit {
+        debug!("cache hit for {}", identity.id);
+        return Ok(Identity::clone(&identity));
+    }
+    let (identity, lifetime) = fetch.await?;
+    let ttl = Duration::from_secs(ttl).min(lifetime.unwrap_or(Duration::MAX));
+    entries.lock().unwrap_or_else(|e| e.into_inner()).insert(
+        key.to_owned(),
+        Arc::new(identity.clone()),
+        ttl,
+        capacity,
+    );
+    Ok(identity)
+}
+
+/// Get the session of a cookie from kratos.
+async fn get_session(config: &SiriusConfig, cookie: &Cookie<'_>) -> Result<Session> {
+    let Some(client) = &config.kratos.client else {
+        bail!("kratos client not initialized")
+    };
+    let addr = format!("{}/sessions/whoami", client.base_path);
+    let response = client
+        .client
+        .get(addr)
+        .header("Cookie", format!("{}={}", cookie.name(), cookie.value()))
+        .send()
+        .await?;
+    response.error_for_status_ref()?;
+    Ok(response.json::<Session>().await?)
+}
+
+/// Time left before a session expires, an inactive or expired session is refused.
+fn session_lifetime(session: &Session) -> Result<Option<Duration>> {
+    if session.active == Some(false) {
+        bail!("session {} is not active", session.id)
+    }
+    let Some(expires_at) = &session.expires_at else {
+        return Ok(None);
+    };
+    let expires_at = OffsetDateTime::parse(expires_at, &Rfc3339)?;
+    let left = Duration::try_from(expires_at - OffsetDateTime::now_utc())
+        .map_err(|_| anyhow!("session {} expired", session.id))?;
+    Ok(Some(left))
+}
+
+/// Validate a kratos session cookie, reusing the identity of a session validated recently.
+/// A session is cached until it expires at most, a revoked session is only refused once its
+/// entry expired, see [`revalidate_session`].
+pub async fn validate_session(config: &SiriusConfig, cookie: &Cookie<'_>) -> Result<Identity> {
+    let fetch = async {
+        let session = get_session(config, cookie).await?;
+        let lifetime = session_lifetime(&session)?;
+        Ok((*session.identity, lifetime))
+    };
+    cached(
+        &config.kratos_cache.sessions,
+        cookie.value(),
+        config.cache.ttl,
+        config.cache.sessions,
+        fetch,
+    )
+    .await
+}
+
+/// Validate a kratos session cookie with kratos whether it is cached or not, used by the
+/// routes writing permissions so that a revoked session can not write.
+pub async fn revalidate_session(config: &SiriusConfig, cookie: &Cookie<'_>) -> Result<Identity> {
+    config
+        .kratos_cache
+        .sessions
+        .lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .remove(cookie.value());
+    validate_session(config, cookie).await
+}
+
+/// Get an identity by id or email through the cache, fetching it with the given future when
+/// missing.
+pub async fn cached_identity<F>(config: &SiriusConfig, key: &str, fetch: F) -> Result<Identity>
+where
+    F: Future<Output = Result<Identity>>,
+{
+    cached(
+        &config.kratos_cache.identities,
+        key,
+        config.cache.ttl,
+        config.cache.identities,
+        async { Ok((fetch.await?, None)) },
+    )
+    .await
+}
+
+#[cfg(test)]
+mod test_cache {
+    use anyhow::bail;
+
+    use super::*;
+    use crate::utils::test::IDENTITY_USER_ADMIN;
+
+    fn identity() -> Identity {
+        serde_json::from_str(IDENTITY_USER_ADMIN).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_cached_identity() {
+        let mut config = SiriusConfig::default();
+        config.cache.ttl = 60;
+        let first = cached_identity(&config, "lol.lol@lol.io", async { Ok(identity()) })
+            .await
+            .unwrap();
+        let hit = cached_identity(&config, "lol.lol@lol.io", async { bail!("not cached") })
+            .await
+            .unwrap();
+        assert_eq!(first, hit);
+        config.kratos_cache.invalidate(&first.id);
+        assert!(config.kratos_cache.is_empty());
+        assert!(
+            cached_identity(&config, "lol.lol@lol.io", async { bail!("not cached") })
+                .await
+                .is_err()
+        );
+        config.cache.ttl = 0;
+        cached_identity(&config, "lol.lol@lol.io", async { Ok(identity()) })
+            .await
+            .unwrap();
+        assert!(config.kratos_cache.is_empty());
+    }
+
+    #[test]
+    fn test_session_lifetime() {
+        let session = |expires_at: OffsetDateTime| {
+            let expires_at = expires_at.format(&Rfc3339).unwrap();
+            serde_json::from_value::<Session>(serde_json::json!({
+                "id": "session",
+                "expires_at": expires_at,
+                "identity": identity()
+            }))
+            .unwrap()
+        };
+        let now = OffsetDateTime::now_utc();
+        let left = session_lifetime(&session(now + time::Duration::seconds(10)))
+            .unwrap()
+            .unwrap();
+        assert!(left <= Duration::from_secs(10));
+        assert!(session_lifetime(&session(now - time::Duration::seconds(10))).is_err());
+        let mut inactive = session(now + time::Duration::seconds(10));
+        inactive.active = Some(false);
+        assert!(session_lifetime(&inactive).is_err());
+    }
+
+    #[test]
+    fn test_capacity() {
+        let mut entries = Entries::default();
+        let identity = Arc::new(identity());
+        for (ttl, key) in [(60, "a"), (61, "b"), (62, "c")] {
+            entries.insert(
+                key.to_owned(),
+                identity.clone(),
+                Duration::from_secs(ttl),
+                2,
+            );
+        }
+        assert_eq!(entries.entries.len(), 2);
+        assert!(entries.get("a").is_none());
+        assert!(entries.get("c").is_some());
+        entries.insert("d".to_owned(), identity, Duration::ZERO, 2);
+        assert!(entries.get("d").is_none());
+    }
+}
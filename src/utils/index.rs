@@ -0,0 +1,334 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex, OnceLock, RwLock},
+};
+
+use anyhow::{anyhow, Result};
+use ory_kratos_client::{apis::identity_api::get_identity, models::Identity};
+use serde::Serialize;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::sync::Semaphore;
+use tracing::{debug, error, info};
+
+use crate::{
+    config::SiriusConfig,
+    controller::list::{list_project_controller, Grant, Source},
+    utils::kratos::list_all_identities,
+};
+
+/// Structure representing an identity having access to a project.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Member {
+    pub id: String,
+    pub roles: Vec<String>,
+    pub source: Source,
+}
+
+/// Structure holding the indexed grants.
+#[derive(Default, Debug)]
+struct IndexData {
+    identities: HashMap<String, HashMap<String, Vec<Grant>>>,
+    projects: HashMap<String, HashSet<String>>,
+    updated: HashMap<String, OffsetDateTime>,
+}
+
+impl IndexData {
+    /// Replace the grants of an identity.
+    fn replace(&mut self, id: &str, grants: HashMap<String, Vec<Grant>>) {
+        if let Some(old) = self.identities.remove(id) {
+            for project in old.keys() {
+                if let Some(members) = self.projects.get_mut(project) {
+                    members.remove(id);
+                    if members.is_empty() {
+                        self.projects.remove(project);
+                    }
+                }
+            }
+        }
+        for project in grants.keys() {
+            self.projects
+                .entry(project.to_owned())
+                .or_default()
+                .insert(id.to_owned());
+        }
+        if !grants.is_empty() {
+            self.identities.insert(id.to_owned(), grants);
+        }
+    }
+}
+
+/// Reverse index of the projects to the identities having access to them.
+/// Kratos can not be queried by metadata so it is maintained from the writes sirius makes.
+#[derive(Default, Debug)]
+pub struct MemberIndex {
+    data: RwLock<IndexData>,
+    pending: Mutex<HashSet<String>>,
+    permits: OnceLock<Arc<Semaphore>>,
+}
+
+impl MemberIndex {
+    /// Replace the grants of an identity in the index.
+    pub fn update(&self, id: &str, grants: HashMap<String, Vec<Grant>>) {
+        let mut data = self.data.write().unwrap_or_else(|e| e.into_inner());
+        data.replace(id, grants);
+    }
+
+    /// Replace the grants of an identity read at the given update time, unless the index holds
+    /// a newer read of it. Return whether the grants were replaced.
+    pub fn update_since(
+        &self,
+        id: &str,
+        updated_at: Option<OffsetDateTime>,
+        grants: HashMap<String, Vec<Grant>>,
+    ) -> bool {
+        let mut data = self.data.write().unwrap_or_else(|e| e.into_inner());
+        if let (Some(updated_at), Some(indexed)) = (updated_at, data.updated.get(id)) {
+            if *indexed > updated_at {
+                return false;
+            }
+        }
+        match updated_at {
+            Some(updated_at) => data.updated.insert(id.to_owned(), updated_at),
+            None => data.updated.remove(id),
+        };
+        data.replace(id, grants);
+        true
+    }
+
+    /// Remove an identity from the index.
+    pub fn remove(&self, id: &str) {
+        let mut data = self.data.write().unwrap_or_else(|e| e.into_inner());
+        data.updated.remove(id);
+        data.replace(id, HashMap::new());
+    }
+
+    /// Mark an identity as waiting for a refresh, return false if it already is.
+    fn schedule(&self, id: &str) -> bool {
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        pending.insert(id.to_owned())
+    }
+
+    /// Unmark an identity waiting for a refresh, the writes made after it schedule another one.
+    fn unschedule(&self, id: &str) {
+        let mut pending = self.pending.lock().unwrap_or_else(|e| e.into_inner());
+        pending.remove(id);
+    }
+
+    /// The permits of the refreshes, the limit of the first call is kept for the index lifetime.
+    fn permits(&self, limit: usize) -> Arc<Semaphore> {
+        self.permits
+            .get_or_init(|| Arc::new(Semaphore::new(limit.max(1))))
+            .clone()
+    }
+
+    /// List the identities having access to a project.
+    pub fn members(&self, project: &str) -> Vec<Member> {
+        let data = self.data.read().unwrap_or_else(|e| e.into_inner());
+        let mut members = Vec::new();
+        let Some(ids) = data.projects.get(project) else {
+            return members;
+        };
+        for id in ids {
+            let Some(grants) = data.identities.get(id).and_then(|g| g.get(project)) else {
+                continue;
+            };
+            for grant in grants {
+                members.push(Member {
+                    id: id.to_owned(),
+                    roles: grant.roles.clone(),
+                    source: grant.source.clone(),
+                });
+            }
+        }
+        members
+    }
+}
+
+/// Index the grants of the given identity.
+/// A read older than the one indexed, from a refresh finishing late, is ignored.
+pub async fn index_identity(config: &SiriusConfig, identity: &Identity) {
+    let updated_at = identity
+        .updated_at
+        .as_deref()
+        .and_then(|updated_at| OffsetDateTime::parse(updated_at, &Rfc3339).ok());
+    match list_project_controller(identity, config).await {
+        Ok(grants) => {
+            if !config.index.update_since(&identity.id, updated_at, grants) {
+                debug!("ignoring an outdated read of identity {}", identity.id);
+            }
+        }
+        Err(e) => {
+            error!("failed to index identity {}: {e}", identity.id);
+            config.index.remove(&identity.id);
+        }
+    }
+}
+
+/// Fetch an identity from kratos and update its grants in the index.
+pub async fn refresh_identity(config: Arc<SiriusConfig>, id: String) {
+    let Some(client) = &config.kratos.client else {
+        error!("kratos client not initialized");
+        return;
+    };
+    match get_identity(client, &id, None).await {
+        Ok(identity) => index_identity(&config, &identity).await,
+        Err(e) => error!("failed to refresh identity {id} in the index: {e}"),
+    }
+}
+
+/// Refresh an identity in the index after a write, in the background.
+/// An identity is waiting for at most one refresh and `limits.kratos` of them are fetched at a
+/// time.
+pub fn schedule_refresh(config: &Arc<SiriusConfig>, id: &str) {
+    if !config.index.schedule(id) {
+        debug!("identity {id} is already waiting for a refresh");
+        return;
+    }
+    let (config, id) = (config.clone(), id.to_owned());
+    tokio::spawn(async move {
+        let permits = config.index.permits(config.limits.kratos);
+        let Ok(_permit) = permits.acquire().await else {
+            return;
+        };
+        config.index.unschedule(&id);
+        refresh_identity(config.clone(), id).await;
+    });
+}
+
+/// Rebuild the index from all the kratos identities.
+pub async fn rebuild_index(config: Arc<SiriusConfig>) -> Result<()> {
+    info!("rebuilding the member index");
+    let identities = list_all_identities(&config)
+        .await
+        .map_err(|e| anyhow!("failed to list identities: {e}"))?;
+    for identity in &identities {
+        index_identity(&config, identity).await;
+    }
+    info!("member index rebuilt");
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_index {
+    use super::*;
+    use mockito::Server as MockServer;
+    use std::time::Duration;
+
+    use crate::utils::test::{configure, IDENTITY_USER_ADMIN};
+
+    fn grant(roles: &[&str], source: Source) -> Vec<Grant> {
+        vec![Grant {
+            roles: roles.iter().map(|role| role.to_string()).collect(),
+            source,
+        }]
+    }
+
+    #[test]
+    fn test_members() {
+        let index = MemberIndex::default();
+        index.update(
+            "user",
+            HashMap::from([("122".to_owned(), grant(&["admin"], Source::Direct))]),
+        );
+        index.update(
+            "other",
+            HashMap::from([(
+                "122".to_owned(),
+                grant(
+                    &["viewer"],
+                    Source::Group {
+                        id: "group".to_owned(),
+                        name: "awesome".to_owned(),
+                    },
+                ),
+            )]),
+        );
+        let mut members = index.members("122");
+        members.sort_by(|a, b| a.id.cmp(&b.id));
+        assert_eq!(members.len(), 2);
+        assert_eq!(members[0].id, "other");
+        assert_eq!(members[1].roles, vec!["admin".to_owned()]);
+        assert!(index.members("334").is_empty());
+    }
+
+    #[test]
+    fn test_update_remove_old_projects() {
+        let index = MemberIndex::default();
+        index.update(
+            "user",
+            HashMap::from([("122".to_owned(), grant(&["admin"], Source::Direct))]),
+        );
+        index.update(
+            "user",
+            HashMap::from([("334".to_owned(), grant(&["admin"], Source::Direct))]),
+        );
+        assert!(index.members("122").is_empty());
+        assert_eq!(index.members("334").len(), 1);
+        index.remove("user");
+        assert!(index.members("334").is_empty());
+    }
+
+    #[test]
+    fn test_update_since() {
+        let index = MemberIndex::default();
+        let read = |at: &str| Some(OffsetDateTime::parse(at, &Rfc3339).unwrap());
+        let newer = read("2023-03-17T14:48:52.1Z");
+        let older = read("2023-03-17T14:48:52.000392Z");
+        assert!(index.update_since(
+            "user",
+            newer,
+            HashMap::from([("334".to_owned(), grant(&["admin"], Source::Direct))]),
+        ));
+        // a refresh finishing after a newer one does not overwrite it
+        assert!(!index.update_since(
+            "user",
+            older,
+            HashMap::from([("122".to_owned(), grant(&["admin"], Source::Direct))]),
+        ));
+        assert!(index.members("122").is_empty());
+        assert_eq!(index.members("334").len(), 1);
+        assert!(index.update_since("user", newer, HashMap::new()));
+        assert!(index.members("334").is_empty());
+        index.remove("user");
+        assert!(index.update_since("user", older, HashMap::new()));
+    }
+
+    #[test]
+    fn test_schedule() {
+        let index = MemberIndex::default();
+        assert!(index.schedule("user"));
+        assert!(!index.schedule("user"));
+        assert!(index.schedule("other"));
+        index.unschedule("user");
+        assert!(index.schedule("user"));
+    }
+
+    #[tokio::test]
+    async fn test_schedule_refresh() {
+        let mut kratos_server = MockServer::new_async().await;
+        let config = Arc::new(configure(Some(&kratos_server), None, None).await);
+        let mock_kratos = kratos_server
+            .mock(
+                "GET",
+                "/admin/identities/af25f904-5319-4011-95a4-343365d64811",
+            )
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(IDENTITY_USER_ADMIN)
+            .expect(1)
+            .create_async()
+            .await;
+        // the writes made before the refresh starts share it
+        for _ in 0..3 {
+            schedule_refresh(&config, "af25f904-5319-4011-95a4-343365d64811");
+        }
+        for _ in 0..50 {
+            if !config.index.members("222").is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        mock_kratos.assert_async().await;
+        assert_eq!(config.index.members("222").len(), 1);
+    }
+}
@@ -0,0 +1,179 @@
+use anyhow::{bail, Result};
+use ory_kratos_client::{apis::identity_api::get_identity, models::Identity};
+use serde_json::{json, Value};
+use tracing::debug;
+
+use crate::{config::SiriusConfig, metadata::Metadata};
+
+/// Number of identities fetched per page when listing all of them.
+pub const PAGE_SIZE: i64 = 250;
+
+/// Read an identity from kratos, bypassing the cache.
+pub async fn read_identity(config: &SiriusConfig, id: &str) -> Result<Identity> {
+    let Some(client) = &config.kratos.client else {
+        bail!("kratos client not initialized")
+    };
+    Ok(get_identity(client, id, None).await?)
+}
+
+/// List a page of identities from kratos.
+pub async fn list_identities(
+    config: &SiriusConfig,
+    page: i64,
+    per_page: i64,
+) -> Result<Vec<Identity>> {
+    let Some(client) = &config.kratos.client else {
+        bail!("kratos client not initialized")
+    };
+    let addr = format!(
+        "{}/admin/identities?page={page}&per_page={per_page}",
+        client.base_path
+    );
+    let response = client.client.get(addr).send().await?;
+    response.error_for_status_ref()?;
+    let identities = response.json::<Vec<Identity>>().await?;
+    debug!("{} identities found in page {page}", identities.len());
+    Ok(identities)
+}
+
+/// List all the identities from kratos, page by page.
+pub async fn list_all_identities(config: &SiriusConfig) -> Result<Vec<Identity>> {
+    let mut identities = Vec::new();
+    let mut page = 1;
+    loop {
+        let found = list_identities(config, page, PAGE_SIZE).await?;
+        let last = (found.len() as i64) < PAGE_SIZE;
+        identities.extend(found);
+        if last {
+            break;
+        }
+        page += 1;
+    }
+    Ok(identities)
+}
+
+/// Create an identity in kratos with the given schema, name and permission metadata.
+/// The metadata is stored where the mode of the opa section of the config point to.
+pub async fn create_identity(
+    config: &SiriusConfig,
+    schema_id: &str,
+    name: &str,
+    metadata: Metadata,
+) -> Result<Identity> {
+    let metadata = serde_json::to_value(metadata)?;
+    let Some(client) = &config.kratos.client else {
+        bail!("kratos client not initialized")
+    };
+    let body = match &config.opa.mode as &str {
+        "admin" => json!({
+            "schema_id": schema_id,
+            "traits": {"name": name},
+            "metadata_admin": metadata
+        }),
+        "public" => json!({
+            "schema_id": schema_id,
+            "traits": {"name": name},
+            "metadata_public": metadata
+        }),
+        "trait" => {
+            let mut traits = metadata;
+            traits["name"] = Value::String(name.to_owned());
+            json!({
+                "schema_id": schema_id,
+                "traits": traits
+            })
+        }
+        _ => bail!("Invalid mode! please put a valid mode (admin, public or trait) in the config"),
+    };
+    let addr = format!("{}/admin/identities", client.base_path);
+    let response = client.client.post(addr).json(&body).send().await?;
+    response.error_for_status_ref()?;
+    let identity = response.json::<Identity>().await?;
+    debug!("identity created: {}", identity.id);
+    Ok(identity)
+}
+
+/// Delete an identity from kratos and drop its cached entries.
+pub async fn delete_identity(config: &SiriusConfig, id: &str) -> Result<()> {
+    let Some(client) = &config.kratos.client else {
+        bail!("kratos client not initialized")
+    };
+    let addr = format!("{}/admin/identities/{id}", client.base_path);
+    let response = client.client.delete(addr).send().await;
+    config.kratos_cache.invalidate(id);
+    response?.error_for_status_ref()?;
+    debug!("identity deleted: {id}");
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_kratos {
+    use mockito::Server as MockServer;
+
+    use super::*;
+    use crate::utils::{
+        cache::cached_identity,
+        test::{configure, IDENTITY_USER_ADMIN},
+    };
+
+    #[tokio::test]
+    async fn test_list_identities() {
+        let mut kratos_server = MockServer::new_async().await;
+        let config = configure(Some(&kratos_server), None, None).await;
+        let body = "[".to_owned() + IDENTITY_USER_ADMIN + "]";
+        let mock_kratos = kratos_server
+            .mock("GET", "/admin/identities?page=1&per_page=250")
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(body)
+            .create_async()
+            .await;
+        let identities = list_identities(&config, 1, 250).await.unwrap();
+        assert_eq!(identities.len(), 1);
+        mock_kratos.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_create_identity() {
+        let mut kratos_server = MockServer::new_async().await;
+        let config = configure(Some(&kratos_server), None, None).await;
+        let mock_kratos = kratos_server
+            .mock("POST", "/admin/identities")
+            .match_body(mockito::Matcher::PartialJson(json!({
+                "schema_id": "organisation",
+                "traits": {"name": "awesome"},
+                "metadata_public": {"version": 1, "project": []}
+            })))
+            .with_status(201)
+            .with_header("content-type", "application/json")
+            .with_body(IDENTITY_USER_ADMIN)
+            .create_async()
+            .await;
+        create_identity(&config, "organisation", "awesome", Metadata::default())
+            .await
+            .unwrap();
+        mock_kratos.assert_async().await;
+    }
+
+    #[tokio::test]
+    async fn test_delete_identity() {
+        let mut kratos_server = MockServer::new_async().await;
+        let mut config = configure(Some(&kratos_server), None, None).await;
+        config.cache.ttl = 60;
+        let mock_kratos = kratos_server
+            .mock(
+                "DELETE",
+                "/admin/identities/af25f904-5319-4011-95a4-343365d64811",
+            )
+            .with_status(204)
+            .create_async()
+            .await;
+        let identity: Identity = serde_json::from_str(IDENTITY_USER_ADMIN).unwrap();
+        cached_identity(&config, &identity.id, async { Ok(identity.clone()) })
+            .await
+            .unwrap();
+        delete_identity(&config, &identity.id).await.unwrap();
+        assert!(config.kratos_cache.is_empty());
+        mock_kratos.assert_async().await;
+    }
+}
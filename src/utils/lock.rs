@@ -0,0 +1,174 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex, Weak},
+    time::Instant,
+};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
+use tracing::{error, info};
+
+/// Backend of a lock shared between the instances of sirius, taken after the in-process
+/// lock of the key.
+#[async_trait]
+pub trait LockBackend: Send + Sync {
+    /// Wait until the lock of the key is acquired.
+    async fn acquire(&self, key: &str) -> Result<()>;
+    /// Release the lock of the key.
+    async fn release(&self, key: &str) -> Result<()>;
+}
+
+/// Lock manager serializing the writes to an identity, the waiters of a key acquire it in
+/// their order of arrival.
+/// The locks are dropped from the manager once nobody holds or waits for them.
+#[derive(Default)]
+pub struct LockManager {
+    locks: Mutex<HashMap<String, Weak<AsyncMutex<()>>>>,
+    backend: Option<Arc<dyn LockBackend>>,
+}
+
+impl fmt::Debug for LockManager {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LockManager")
+            .field("locks", &self.locks)
+            .field("backend", &self.backend.is_some())
+            .finish()
+    }
+}
+
+/// Guard of the lock of a key, the lock is released when it is dropped.
+pub struct LockGuard {
+    key: String,
+    backend: Option<Arc<dyn LockBackend>>,
+    _local: OwnedMutexGuard<()>,
+}
+
+impl Drop for LockGuard {
+    fn drop(&mut self) {
+        if let Some(backend) = self.backend.take() {
+            let key = std::mem::take(&mut self.key);
+            tokio::spawn(async move {
+                if let Err(e) = backend.release(&key).await {
+                    error!("failed to release the lock of {key}: {e}");
+                }
+            });
+        }
+    }
+}
+
+impl LockManager {
+    /// Create a lock manager also taking the locks on a distributed backend.
+    pub fn with_backend(backend: Arc<dyn LockBackend>) -> Self {
+        LockManager {
+            locks: Mutex::default(),
+            backend: Some(backend),
+        }
+    }
+
+    /// Get the lock of a key, creating it if nobody holds it.
+    fn entry(&self, key: &str) -> Arc<AsyncMutex<()>> {
+        let mut locks = self.locks.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(lock) = locks.get(key).and_then(Weak::upgrade) {
+            return lock;
+        }
+        locks.retain(|_, lock| lock.strong_count() > 0);
+        let lock = Arc::new(AsyncMutex::new(()));
+        locks.insert(key.to_owned(), Arc::downgrade(&lock));
+        lock
+    }
+
+    /// Wait for the lock of a key, the time spent waiting is logged.
+    pub async fn lock(&self, key: &str) -> Result<LockGuard> {
+        let start = Instant::now();
+        let local = self.entry(key).lock_owned().await;
+        if let Some(backend) = &self.backend {
+            backend.acquire(key).await?;
+        }
+        info!(
+            "lock of {key} acquired after {}ms",
+            start.elapsed().as_millis()
+        );
+        Ok(LockGuard {
+            key: key.to_owned(),
+            backend: self.backend.clone(),
+            _local: local,
+        })
+    }
+
+    /// Number of keys locked or waited for.
+    pub fn len(&self) -> usize {
+        let locks = self.locks.lock().unwrap_or_else(|e| e.into_inner());
+        locks
+            .values()
+            .filter(|lock| lock.strong_count() > 0)
+            .count()
+    }
+
+    /// Check if no key is locked.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod test_lock {
+    use std::{sync::atomic::AtomicUsize, sync::atomic::Ordering, time::Duration};
+
+    use super::*;
+
+    #[derive(Default)]
+    struct CountingBackend {
+        held: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl LockBackend for CountingBackend {
+        async fn acquire(&self, _key: &str) -> Result<()> {
+            self.held.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+        async fn release(&self, _key: &str) -> Result<()> {
+            self.held.fetch_sub(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lock_order() {
+        let locks = Arc::new(LockManager::default());
+        let order = Arc::new(Mutex::new(Vec::new()));
+        let guard = locks.lock("identity").await.unwrap();
+        let mut handles = Vec::new();
+        for index in 0..3 {
+            let (locks, order) = (locks.clone(), order.clone());
+            handles.push(tokio::spawn(async move {
+                let _guard = locks.lock("identity").await.unwrap();
+                order.lock().unwrap().push(index);
+            }));
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let other = locks.lock("other").await.unwrap();
+        assert_eq!(locks.len(), 2);
+        assert!(order.lock().unwrap().is_empty());
+        drop(guard);
+        drop(other);
+        for handle in handles {
+            handle.await.unwrap();
+        }
+        assert_eq!(*order.lock().unwrap(), [0, 1, 2]);
+        assert!(locks.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_lock_backend() {
+        let backend = Arc::new(CountingBackend::default());
+        let locks = LockManager::with_backend(backend.clone());
+        let guard = locks.lock("identity").await.unwrap();
+        assert_eq!(backend.held.load(Ordering::SeqCst), 1);
+        drop(guard);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(backend.held.load(Ordering::SeqCst), 0);
+    }
+}
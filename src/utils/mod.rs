@@ -1,6 +1,12 @@
+pub mod authz;
+pub mod cache;
 pub mod error;
+pub mod index;
 pub mod kafka;
+pub mod kratos;
+pub mod lock;
 #[cfg(feature = "opa")]
 pub mod opa;
+pub mod queue;
 #[cfg(test)]
 pub mod test;
@@ -29,10 +29,11 @@ pub async fn validate_roles(
     project_id: &str,
     correlation_id: &str,
     uri: &str,
+    method: &str,
 ) -> Result<bool> {
     let input = Input {
         uri,
-        method: "post",
+        method,
         role: "unused", //get from conf file
         resource: project_id,
     };
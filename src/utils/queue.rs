@@ -0,0 +1,573 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    path::Path,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{anyhow, bail, Result};
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+use sled::{Db, Tree};
+use stream_cancel::Tripwire;
+use tokio::{sync::Notify, task::JoinHandle};
+use tracing::{error, info, warn};
+use uuid::Uuid;
+
+use crate::{
+    config::{Jobs, SiriusConfig},
+    controller::sync::{sync_with_report, SyncMode, SyncReport},
+    utils::{error::send_error, kafka::send_to_kafka},
+    ConfigState,
+};
+
+/// Time waited by the worker when no job is pending.
+const IDLE: Duration = Duration::from_secs(60);
+
+/// Get the current time in milliseconds since the epoch.
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Enum representing the state of a sync job.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    #[default]
+    Pending,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+/// Structure representing a sync job with the id of the group to sync, the group is read
+/// again when the job runs.
+/// The owner is the identity which requested the update of the group.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Job {
+    pub id: String,
+    pub owner: String,
+    pub group: String,
+    pub modes: Vec<SyncMode>,
+    pub correlation_id: String,
+    #[serde(default)]
+    pub status: JobStatus,
+    pub attempts: u32,
+    pub next_attempt: u64,
+    #[serde(default)]
+    pub report: SyncReport,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub finished: Option<u64>,
+}
+
+impl Job {
+    pub fn new(group: &str, owner: &str, correlation_id: &str, modes: Vec<SyncMode>) -> Self {
+        Job {
+            id: Uuid::new_v4().to_string(),
+            owner: owner.to_owned(),
+            group: group.to_owned(),
+            modes,
+            correlation_id: correlation_id.to_owned(),
+            status: JobStatus::Pending,
+            attempts: 0,
+            next_attempt: now(),
+            report: SyncReport::default(),
+            error: None,
+            finished: None,
+        }
+    }
+}
+
+/// Structure representing the state of a job returned to its owner.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct JobReport {
+    pub id: String,
+    pub status: JobStatus,
+    pub attempts: u32,
+    pub patched: usize,
+    pub errors: HashMap<String, String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub conflicts: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl From<&Job> for JobReport {
+    fn from(job: &Job) -> Self {
+        JobReport {
+            id: job.id.clone(),
+            status: job.status,
+            attempts: job.attempts,
+            patched: job.report.patched,
+            errors: job.report.errors.clone(),
+            conflicts: job.report.conflicts.clone(),
+            error: job.error.clone(),
+        }
+    }
+}
+
+/// Queue of the sync jobs persisted in an embedded database.
+/// The pending jobs survive a restart and the jobs failing too many times are dead-lettered,
+/// the succeeded ones are kept until the end of the retention of the config.
+pub struct JobQueue {
+    db: Db,
+    pending: Tree,
+    done: Tree,
+    dead: Tree,
+    notify: Notify,
+}
+
+impl fmt::Debug for JobQueue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JobQueue")
+            .field("pending", &self.pending.len())
+            .field("done", &self.done.len())
+            .field("dead", &self.dead.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl JobQueue {
+    /// Open the queue stored at the given path.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::from_db(sled::open(path)?)
+    }
+
+    /// Open a queue removed when dropped.
+    #[cfg(test)]
+    pub fn temporary() -> Result<Self> {
+        Self::from_db(sled::Config::new().temporary(true).open()?)
+    }
+
+    fn from_db(db: Db) -> Result<Self> {
+        Ok(JobQueue {
+            pending: db.open_tree("pending")?,
+            done: db.open_tree("done")?,
+            dead: db.open_tree("dead")?,
+            db,
+            notify: Notify::new(),
+        })
+    }
+
+    fn save(tree: &Tree, job: &Job) -> Result<()> {
+        tree.insert(job.id.as_bytes(), serde_json::to_vec(job)?)?;
+        Ok(())
+    }
+
+    fn list(tree: &Tree) -> Result<Vec<Job>> {
+        let mut jobs = Vec::new();
+        for entry in tree.iter() {
+            let (id, job) = entry?;
+            match serde_json::from_slice(&job) {
+                Ok(job) => jobs.push(job),
+                Err(e) => error!("corrupted job {}: {e}", String::from_utf8_lossy(&id)),
+            }
+        }
+        Ok(jobs)
+    }
+
+    fn load(tree: &Tree, id: &str) -> Result<Option<Job>> {
+        match tree.get(id.as_bytes())? {
+            Some(job) => Ok(Some(serde_json::from_slice(&job)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Persist a new job on disk then wake up the worker.
+    pub async fn push(&self, job: &Job) -> Result<()> {
+        Self::save(&self.pending, job)?;
+        self.db.flush_async().await?;
+        self.notify.notify_one();
+        Ok(())
+    }
+
+    /// List the jobs waiting to be run.
+    pub fn pending(&self) -> Result<Vec<Job>> {
+        Self::list(&self.pending)
+    }
+
+    /// List the dead-lettered jobs.
+    pub fn dead(&self) -> Result<Vec<Job>> {
+        Self::list(&self.dead)
+    }
+
+    /// Get a job whatever its state.
+    pub fn get(&self, id: &str) -> Result<Option<Job>> {
+        for tree in [&self.pending, &self.done, &self.dead] {
+            if let Some(job) = Self::load(tree, id)? {
+                return Ok(Some(job));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Mark a job as running.
+    pub async fn start(&self, job: &mut Job) -> Result<()> {
+        job.status = JobStatus::Running;
+        Self::save(&self.pending, job)?;
+        self.db.flush_async().await?;
+        Ok(())
+    }
+
+    /// Move a job run successfully to the finished jobs.
+    pub async fn complete(&self, job: &mut Job) -> Result<()> {
+        job.status = JobStatus::Succeeded;
+        job.finished = Some(now());
+        Self::save(&self.done, job)?;
+        self.pending.remove(job.id.as_bytes())?;
+        self.db.flush_async().await?;
+        Ok(())
+    }
+
+    /// Save a failed job to be run again.
+    pub async fn retry(&self, job: &mut Job) -> Result<()> {
+        job.status = JobStatus::Pending;
+        Self::save(&self.pending, job)?;
+        self.db.flush_async().await?;
+        Ok(())
+    }
+
+    /// Move a job failing too many times to the dead letters.
+    pub async fn dead_letter(&self, job: &mut Job) -> Result<()> {
+        job.status = JobStatus::Failed;
+        job.finished = Some(now());
+        Self::save(&self.dead, job)?;
+        self.pending.remove(job.id.as_bytes())?;
+        self.db.flush_async().await?;
+        Ok(())
+    }
+
+    /// Remove the succeeded jobs finished for longer than the retention.
+    pub fn prune(&self, retention: Duration) -> Result<usize> {
+        let limit = now().saturating_sub(retention.as_millis() as u64);
+        let mut pruned = 0;
+        for job in Self::list(&self.done)? {
+            if job.finished.unwrap_or_default() < limit {
+                self.done.remove(job.id.as_bytes())?;
+                pruned += 1;
+            }
+        }
+        Ok(pruned)
+    }
+
+    /// Wait for a new job to be pushed.
+    pub async fn notified(&self) {
+        self.notify.notified().await;
+    }
+}
+
+/// Persist a sync job in the queue of the config, the job is on disk once this returns.
+pub async fn enqueue(
+    config: &SiriusConfig,
+    group: &str,
+    owner: &str,
+    correlation_id: &str,
+    modes: Vec<SyncMode>,
+) -> Result<Job> {
+    let Some(queue) = &config.queue else {
+        bail!("job queue not initialized")
+    };
+    let job = Job::new(group, owner, correlation_id, modes);
+    queue.push(&job).await?;
+    info!("sync job {} queued", job.id);
+    Ok(job)
+}
+
+/// Compute the delay before the next attempt of a job, doubling on every failure.
+fn backoff(settings: &Jobs, attempts: u32) -> Duration {
+    let factor = 2u64.saturating_pow(attempts.saturating_sub(1));
+    Duration::from_secs(
+        settings
+            .backoff
+            .saturating_mul(factor)
+            .min(settings.max_backoff),
+    )
+}
+
+/// Run every sync of a job, collecting the patched users and their errors in its report.
+async fn sync_job(config: &Arc<SiriusConfig>, job: &mut Job) -> Result<()> {
+    job.report = SyncReport::default();
+    for mode in &job.modes {
+        let report = sync_with_report(config, &job.group, mode.clone()).await?;
+        job.report.merge(report);
+    }
+    job.report.clone().into_result()
+}
+
+/// Run a job, it is moved to the finished jobs if it succeed, otherwise retried later or
+/// dead-lettered once the max attempts of the config is reached.
+async fn run_job(config: &Arc<SiriusConfig>, queue: &JobQueue, mut job: Job) -> Result<()> {
+    info!("running sync job {}, attempt {}", job.id, job.attempts + 1);
+    queue.start(&mut job).await?;
+    let e = match sync_job(config, &mut job).await {
+        Ok(()) => {
+            job.error = None;
+            queue.complete(&mut job).await?;
+            if let Err(e) = send_to_kafka(&config.kafka, "notif", "ok", None).await {
+                error!("{e}");
+            }
+            info!("data synced successfully!");
+            return Ok(());
+        }
+        Err(e) => e,
+    };
+    job.attempts += 1;
+    job.error = Some(e.to_string());
+    if job.attempts >= config.jobs.max_attempts {
+        warn!("sync job {} dead-lettered: {e}", job.id);
+        queue.dead_letter(&mut job).await?;
+        if let Err(e) = send_error(&config.kafka, "error", &*e, &job.correlation_id).await {
+            error!("{e}");
+        }
+        if let Err(e) = send_to_kafka(&config.kafka, "notif", "ko", None).await {
+            error!("{e}");
+        }
+    } else {
+        let delay = backoff(&config.jobs, job.attempts);
+        warn!("sync job {} failed, retrying in {delay:?}: {e}", job.id);
+        job.next_attempt = now() + delay.as_millis() as u64;
+        queue.retry(&mut job).await?;
+    }
+    Ok(())
+}
+
+/// Run the jobs whose next attempt is due and return the time to wait for the next one.
+/// No new job is started once the tripwire is triggered.
+pub async fn process_due(
+    config: &Arc<SiriusConfig>,
+    queue: &JobQueue,
+    shutdown: &Tripwire,
+) -> Result<Duration> {
+    let pruned = queue.prune(Duration::from_secs(config.jobs.retention))?;
+    if pruned > 0 {
+        info!("{pruned} finished jobs pruned");
+    }
+    let mut wait = IDLE;
+    for job in queue.pending()? {
+        if shutdown.clone().now_or_never().is_some() {
+            info!("shutting down, the remaining jobs are left in the queue");
+            break;
+        }
+        let now = now();
+        if job.next_attempt > now {
+            wait = wait.min(Duration::from_millis(job.next_attempt - now));
+            continue;
+        }
+        run_job(config, queue, job).await?;
+        wait = wait.min(backoff(&config.jobs, 1));
+    }
+    Ok(wait)
+}
+
+/// Run the queued jobs until the tripwire is triggered.
+/// The jobs left in the queue by a previous run are replayed first.
+#[cfg(not(tarpaulin_include))]
+pub async fn run_queue(state: ConfigState, shutdown: Tripwire) {
+    info!("starting the job queue worker");
+    loop {
+        let config = Arc::new(state.read().await.clone());
+        let Some(queue) = config.queue.clone() else {
+            error!("job queue not initialized");
+            return;
+        };
+        let wait = match process_due(&config, &queue, &shutdown).await {
+            Ok(wait) => wait,
+            Err(e) => {
+                error!("failed to process the job queue: {e}");
+                backoff(&config.jobs, 1)
+            }
+        };
+        tokio::select! {
+            biased;
+            _ = shutdown.clone() => break,
+            () = queue.notified() => (),
+            () = tokio::time::sleep(wait) => (),
+        }
+    }
+    info!("job queue worker stopped");
+}
+
+/// Put the jobs interrupted while running back in the queue, to be replayed on the next start,
+/// and report them as errors.
+pub async fn checkpoint(config: &SiriusConfig, queue: &JobQueue) -> Result<usize> {
+    let mut interrupted = 0;
+    for mut job in queue.pending()? {
+        if job.status != JobStatus::Running {
+            continue;
+        }
+        let e = anyhow!("sync job {} interrupted by the shutdown", job.id);
+        warn!("{e}");
+        job.error = Some(e.to_string());
+        queue.retry(&mut job).await?;
+        if let Err(e) = send_error(&config.kafka, "error", &*e, &job.correlation_id).await {
+            error!("{e}");
+        }
+        interrupted += 1;
+    }
+    Ok(interrupted)
+}
+
+/// Wait for the worker to finish its running job until the drain deadline of the config,
+/// then stop it and checkpoint the job it was running.
+#[cfg(not(tarpaulin_include))]
+pub async fn drain_queue(state: &ConfigState, mut worker: JoinHandle<()>) -> Result<()> {
+    let config = state.read().await.clone();
+    let deadline = Duration::from_secs(config.jobs.drain);
+    info!("draining the job queue for at most {deadline:?}");
+    if tokio::time::timeout(deadline, &mut worker).await.is_ok() {
+        info!("job queue drained");
+        return Ok(());
+    }
+    warn!("job queue not drained before the deadline, stopping the worker");
+    worker.abort();
+    if let Err(e) = worker.await {
+        if !e.is_cancelled() {
+            error!("job queue worker failed: {e}");
+        }
+    }
+    let Some(queue) = &config.queue else {
+        return Ok(());
+    };
+    let interrupted = checkpoint(&config, queue).await?;
+    info!("{interrupted} interrupted jobs checkpointed");
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_queue {
+    use mockito::Server as MockServer;
+
+    use super::*;
+    use crate::utils::test::{configure, IDENTITY_GROUP_ADMIN};
+
+    const GROUP: &str = "af25f904-5319-4011-95a4-343365d64811";
+
+    #[tokio::test]
+    async fn test_push_complete() {
+        let queue = JobQueue::temporary().unwrap();
+        let mut job = Job::new(
+            GROUP,
+            "owner",
+            "1",
+            vec![SyncMode::RemoveProject(vec!["122".to_owned()])],
+        );
+        queue.push(&job).await.unwrap();
+        let pending = queue.pending().unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].id, job.id);
+        assert_eq!(pending[0].status, JobStatus::Pending);
+        queue.complete(&mut job).await.unwrap();
+        assert!(queue.pending().unwrap().is_empty());
+        let done = queue.get(&job.id).unwrap().unwrap();
+        assert_eq!(done.status, JobStatus::Succeeded);
+        assert_eq!(queue.prune(Duration::from_secs(60)).unwrap(), 0);
+        std::thread::sleep(Duration::from_millis(2));
+        assert_eq!(queue.prune(Duration::ZERO).unwrap(), 1);
+        assert!(queue.get(&job.id).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_backoff() {
+        let settings = Jobs {
+            backoff: 2,
+            max_backoff: 10,
+            ..Default::default()
+        };
+        assert_eq!(backoff(&settings, 1), Duration::from_secs(2));
+        assert_eq!(backoff(&settings, 3), Duration::from_secs(8));
+        assert_eq!(backoff(&settings, 30), Duration::from_secs(10));
+    }
+
+    #[tokio::test]
+    async fn test_checkpoint() {
+        let config = configure(None, None, None).await;
+        let queue = config.queue.clone().unwrap();
+        let mut running = Job::new(GROUP, "owner", "1", Vec::new());
+        let waiting = Job::new(GROUP, "owner", "2", Vec::new());
+        queue.push(&running).await.unwrap();
+        queue.push(&waiting).await.unwrap();
+        queue.start(&mut running).await.unwrap();
+        assert_eq!(checkpoint(&config, &queue).await.unwrap(), 1);
+        let job = queue.get(&running.id).unwrap().unwrap();
+        assert_eq!(job.status, JobStatus::Pending);
+        assert!(job.error.is_some());
+        assert!(queue.get(&waiting.id).unwrap().unwrap().error.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_process_due_shutdown() {
+        let config = Arc::new(configure(None, None, None).await);
+        let queue = config.queue.clone().unwrap();
+        let job = Job::new(GROUP, "owner", "1", Vec::new());
+        queue.push(&job).await.unwrap();
+        let (trigger, shutdown) = Tripwire::new();
+        drop(trigger);
+        process_due(&config, &queue, &shutdown).await.unwrap();
+        assert_eq!(queue.pending().unwrap()[0].status, JobStatus::Pending);
+    }
+
+    #[tokio::test]
+    async fn test_process_due() {
+        let (_trigger, shutdown) = Tripwire::new();
+        let mut kratos_server = MockServer::new_async().await;
+        let kratos_mock = kratos_server
+            .mock(
+                "GET",
+                "/admin/identities/af25f904-5319-4011-95a4-343365d64811",
+            )
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(IDENTITY_GROUP_ADMIN)
+            .expect_at_least(1)
+            .create_async()
+            .await;
+        let mut config = configure(Some(&kratos_server), None, None).await;
+        config.jobs.max_attempts = 2;
+        let config = Arc::new(config);
+        let queue = config.queue.clone().unwrap();
+        let synced = enqueue(
+            &config,
+            GROUP,
+            "owner",
+            "1",
+            vec![SyncMode::Project(vec!["1".to_owned()])],
+        )
+        .await
+        .unwrap();
+        // a group missing from kratos fails the job
+        let job = enqueue(
+            &config,
+            "broken",
+            "owner",
+            "2",
+            vec![SyncMode::RemoveUser(Vec::new())],
+        )
+        .await
+        .unwrap();
+        process_due(&config, &queue, &shutdown).await.unwrap();
+        let pending = queue.pending().unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].id, job.id);
+        assert_eq!(pending[0].attempts, 1);
+        assert_eq!(pending[0].status, JobStatus::Pending);
+        let report = JobReport::from(&queue.get(&synced.id).unwrap().unwrap());
+        assert_eq!(report.status, JobStatus::Succeeded);
+        assert_eq!(report.patched, 1);
+        kratos_mock.assert_async().await;
+        let mut retried = pending[0].clone();
+        retried.next_attempt = 0;
+        queue.retry(&mut retried).await.unwrap();
+        process_due(&config, &queue, &shutdown).await.unwrap();
+        assert!(queue.pending().unwrap().is_empty());
+        let dead = queue.dead().unwrap();
+        assert_eq!(dead[0].id, job.id);
+        assert_eq!(dead[0].status, JobStatus::Failed);
+        assert!(dead[0].error.is_some());
+    }
+}
@@ -1,3 +1,5 @@
+use std::sync::Arc;
+
 use mockito::Server as MockServer;
 use tonic::{
     async_trait,
@@ -15,6 +17,7 @@ use crate::{
         iam_server::{Iam as IamTrait, IamServer},
         Input, Reply,
     },
+    utils::queue::JobQueue,
 };
 
 pub static IDENTITY_ORG: &str = r#"
@@ -152,6 +155,172 @@ pub static IDENTITY_USER: &str = r#"
         "updated_at":"2023-03-17T14:48:52.000392Z"
     }"#;
 
+/// Organisation whose user is admin of it.
+pub static IDENTITY_ORG_ADMIN: &str = r#"
+    {
+        "id":"af25f904-5319-4011-95a4-343365d64811",
+        "schema_id":"default",
+        "schema_url":"http://127.0.0.1:4433/schemas/ZGVmYXVsdA",
+        "state":"active",
+        "state_changed_at":"2023-03-17T14:48:51.999240392Z",
+        "traits":{
+            "email":"lol.lol@lol.io",
+            "name":{
+                "first":"lol",
+                "last":"lol"
+            }
+        },
+        "verifiable_addresses":[
+            {
+                "id":"9a93298f-50c5-4ee0-a9b9-95632da77cd7",
+                "value":"lol.lol@lol.io",
+                "verified":false,
+                "via":"email",
+                "status":"sent",
+                "created_at":"2023-03-17T14:48:52.000813Z",
+                "updated_at":"2023-03-17T14:48:52.000813Z"
+            }
+        ],
+        "recovery_addresses":[
+            {
+                "id":"2a675f07-c733-4dce-a280-1fb9054d4a74",
+                "value":"lol.lol@lol.io",
+                "via":"email",
+                "created_at":"2023-03-17T14:48:52.001108Z",
+                "updated_at":"2023-03-17T14:48:52.001108Z"
+            }
+        ],
+        "metadata_admin":null,
+        "metadata_public":{
+            "project":{},
+            "group":{
+                "7113206d-afc0-41ad-bbca-b1e8113beb82": "default"
+            },
+            "user":{
+                "af25f904-5319-4011-95a4-343365d64811": ["admin"]
+            }
+        },
+        "created_at":"2023-03-17T14:48:52.000392Z",
+        "updated_at":"2023-03-17T14:48:52.000392Z"
+    }"#;
+
+/// Group whose user is admin of it.
+pub static IDENTITY_GROUP_ADMIN: &str = r#"
+    {
+        "id":"af25f904-5319-4011-95a4-343365d64811",
+        "schema_id":"default",
+        "schema_url":"http://127.0.0.1:4433/schemas/ZGVmYXVsdA",
+        "state":"active",
+        "state_changed_at":"2023-03-17T14:48:51.999240392Z",
+        "traits":{
+            "email":"lol.lol@lol.io",
+            "name":{
+                "first":"lol",
+                "last":"lol"
+            }
+        },
+        "verifiable_addresses":[
+            {
+                "id":"9a93298f-50c5-4ee0-a9b9-95632da77cd7",
+                "value":"lol.lol@lol.io",
+                "verified":false,
+                "via":"email",
+                "status":"sent",
+                "created_at":"2023-03-17T14:48:52.000813Z",
+                "updated_at":"2023-03-17T14:48:52.000813Z"
+            }
+        ],
+        "recovery_addresses":[
+            {
+                "id":"2a675f07-c733-4dce-a280-1fb9054d4a74",
+                "value":"lol.lol@lol.io",
+                "via":"email",
+                "created_at":"2023-03-17T14:48:52.001108Z",
+                "updated_at":"2023-03-17T14:48:52.001108Z"
+            }
+        ],
+        "metadata_admin":null,
+        "metadata_public":{
+            "project":[122, 334, 456],
+            "user":{
+                "af25f904-5319-4011-95a4-343365d64811": ["admin"]
+            }
+        },
+        "created_at":"2023-03-17T14:48:52.000392Z",
+        "updated_at":"2023-03-17T14:48:52.000392Z"
+    }"#;
+
+/// User holding admin roles on a project and on two groups.
+pub static IDENTITY_USER_ADMIN: &str = r#"
+    {
+        "id":"af25f904-5319-4011-95a4-343365d64811",
+        "schema_id":"default",
+        "schema_url":"http://127.0.0.1:4433/schemas/ZGVmYXVsdA",
+        "state":"active",
+        "state_changed_at":"2023-03-17T14:48:51.999240392Z",
+        "traits":{
+            "email":"lol.lol@lol.io",
+            "name":{
+                "first":"lol",
+                "last":"lol"
+            }
+        },
+        "verifiable_addresses":[
+            {
+                "id":"9a93298f-50c5-4ee0-a9b9-95632da77cd7",
+                "value":"lol.lol@lol.io",
+                "verified":false,
+                "via":"email",
+                "status":"sent",
+                "created_at":"2023-03-17T14:48:52.000813Z",
+                "updated_at":"2023-03-17T14:48:52.000813Z"
+            }
+        ],
+        "recovery_addresses":[
+            {
+                "id":"2a675f07-c733-4dce-a280-1fb9054d4a74",
+                "value":"lol.lol@lol.io",
+                "via":"email",
+                "created_at":"2023-03-17T14:48:52.001108Z",
+                "updated_at":"2023-03-17T14:48:52.001108Z"
+            }
+        ],
+        "metadata_admin":null,
+        "metadata_public":{
+            "project":{
+                "222": ["admin"]
+            },
+            "group":{
+                "7113206d-afc0-41ad-bbca-b1e8113beb82": {
+                    "name" : "awesome",
+                    "project" : [122, 334, 456],
+                    "role" : ["admin"]
+                },
+                "9f425a8d-7efc-4768-8f23-7647a74fdf13": {
+                    "name" : "kratos",
+                    "project" : [],
+                    "role" : ["admin"]
+                }
+            }
+        },
+        "created_at":"2023-03-17T14:48:52.000392Z",
+        "updated_at":"2023-03-17T14:48:52.000392Z"
+    }"#;
+
+/// Resource on which the mocked iam always fails.
+pub static FAILING_RESOURCE: &str = "fail";
+
+fn failing(req: &Request<Input>) -> bool {
+    req.get_ref().resource == FAILING_RESOURCE
+}
+
+/// Version of a group always modified by a concurrent update on the mocked iam.
+pub static STALE_VERSION: &str = "stale";
+
+fn stale(req: &Request<Input>) -> bool {
+    req.get_ref().expected_version == STALE_VERSION
+}
+
 #[derive(Default)]
 pub struct MyIam {}
 
@@ -160,14 +329,32 @@ impl IamTrait for MyIam {
     ///grpc route to replace an identity field
     async fn add_permission(&self, req: Request<Input>) -> Result<Response<Reply>, Status> {
         println!("replace: Got a request: {:?}", req);
+        if failing(&req) {
+            return Err(Status::internal("failing resource"));
+        }
+        if stale(&req) {
+            return Err(Status::aborted("identity modified since it was read"));
+        }
         Ok(Response::new(Reply {}))
     }
     async fn remove_permission(&self, req: Request<Input>) -> Result<Response<Reply>, Status> {
         println!("replace: Got a request: {:?}", req);
+        if failing(&req) {
+            return Err(Status::internal("failing resource"));
+        }
+        if stale(&req) {
+            return Err(Status::aborted("identity modified since it was read"));
+        }
         Ok(Response::new(Reply {}))
     }
     async fn replace_permission(&self, req: Request<Input>) -> Result<Response<Reply>, Status> {
         println!("replace: Got a request: {:?}", req);
+        if failing(&req) {
+            return Err(Status::internal("failing resource"));
+        }
+        if stale(&req) {
+            return Err(Status::aborted("identity modified since it was read"));
+        }
         Ok(Response::new(Reply {}))
     }
 }
@@ -245,8 +432,11 @@ pub async fn configure(
                 health: port.to_owned(),
             },
         },
+        versioned: false,
+        whole_field: false,
         client: Some(mock_grpc_server().await),
     };
     conf.kratos = kratos;
+    conf.queue = Some(Arc::new(JobQueue::temporary().unwrap()));
     conf
 }